@@ -0,0 +1,640 @@
+//! Native rendering of a BibTeX bibliography to html.
+
+use fs_err as fs;
+use itertools::Itertools;
+use nom_bibtex::{Bibliography, Bibtex};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::path::Path;
+
+use crate::errors::*;
+use crate::types::CitationStyle;
+
+/// Source path of the generated bibliography chapter
+pub const BIBLIOGRAPHY_PATH: &str = "bibliography.md";
+
+/// Name of an author, split into given names and family name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Author {
+    pub first: String,
+    pub last: String,
+}
+
+impl std::fmt::Display for Author {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.first.is_empty() {
+            f.write_str(&self.last)
+        } else {
+            write!(f, "{} {}", self.first, self.last)
+        }
+    }
+}
+
+/// A single entry of the bibliography, with the TeX markup stripped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BibEntry {
+    /// Citation key, used as anchor.
+    pub key: String,
+    /// Type of the entry, i.e. `article` or `book`.
+    pub kind: String,
+    pub authors: Vec<Author>,
+    pub title: Option<String>,
+    /// Journal, book title or publisher, whatever is present first.
+    pub venue: Option<String>,
+    pub year: Option<String>,
+    pub doi: Option<String>,
+    pub url: Option<String>,
+}
+
+impl BibEntry {
+    fn from_bibtex(bib: &Bibliography) -> Self {
+        let tag = |name: &str| {
+            bib.tags()
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| strip_tex(value))
+                .filter(|value| !value.is_empty())
+        };
+        let authors = tag("author")
+            .or_else(|| tag("editor"))
+            .map(|authors| split_authors(&authors))
+            .unwrap_or_default();
+
+        Self {
+            key: bib.citation_key().to_owned(),
+            kind: bib.entry_type().to_lowercase(),
+            authors,
+            title: tag("title"),
+            venue: tag("journal")
+                .or_else(|| tag("booktitle"))
+                .or_else(|| tag("publisher"))
+                .or_else(|| tag("school"))
+                .or_else(|| tag("institution")),
+            year: tag("year"),
+            doi: tag("doi"),
+            url: tag("url"),
+        }
+    }
+}
+
+/// Read and parse all entries of a BibTeX file, in file order.
+pub fn load(path: &Path) -> Result<Vec<BibEntry>> {
+    if !path.exists() {
+        return Err(Error::BibliographyMissing(path.display().to_string()));
+    }
+    let bibtex = fs::read_to_string(path)?;
+    let bibtex = Bibtex::parse(&bibtex)?;
+    Ok(bibtex
+        .bibliographies()
+        .iter()
+        .map(BibEntry::from_bibtex)
+        .collect())
+}
+
+/// Remove braces and commands, and resolve the most common TeX escapes.
+fn strip_tex(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' | '}' => {}
+            '~' => out.push('\u{a0}'),
+            '\\' => match chars.peek().copied() {
+                Some(escaped @ ('&' | '%' | '$' | '#' | '_' | '{' | '}' | ' ')) => {
+                    out.push(escaped);
+                    chars.next();
+                }
+                Some(accent @ ('"' | '\'' | '`' | '^' | '~')) => {
+                    chars.next();
+                    while chars.peek() == Some(&'{') {
+                        chars.next();
+                    }
+                    if let Some(base) = chars.next() {
+                        out.push(base);
+                    }
+                    out.push(match accent {
+                        '"' => '\u{308}',
+                        '\'' => '\u{301}',
+                        '`' => '\u{300}',
+                        '^' => '\u{302}',
+                        _ => '\u{303}',
+                    });
+                }
+                Some(letter) if letter.is_ascii_alphabetic() => {
+                    let mut name = String::new();
+                    while let Some(letter) = chars.next_if(char::is_ascii_alphabetic) {
+                        name.push(letter);
+                    }
+                    // commands with an argument, like `\emph{..}`, only keep the latter
+                    if chars.peek() != Some(&'{') {
+                        out.push_str(&name);
+                    }
+                }
+                _ => out.push(c),
+            },
+            '-' if chars.peek() == Some(&'-') => {
+                chars.next();
+                if chars.peek() == Some(&'-') {
+                    chars.next();
+                    out.push('—');
+                } else {
+                    out.push('–');
+                }
+            }
+            c if c.is_whitespace() => {
+                if !out.ends_with(' ') {
+                    out.push(' ');
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    out.trim().to_owned()
+}
+
+/// Split a BibTeX author list, either given as `Last, First` or `First Last` each.
+fn split_authors(authors: &str) -> Vec<Author> {
+    authors
+        .split(" and ")
+        .map(str::trim)
+        .filter(|author| !author.is_empty())
+        .map(|author| match author.split_once(',') {
+            Some((last, first)) => Author {
+                first: first.trim().to_owned(),
+                last: last.trim().to_owned(),
+            },
+            None => match author.rsplit_once(' ') {
+                Some((first, last)) => Author {
+                    first: first.trim().to_owned(),
+                    last: last.to_owned(),
+                },
+                None => Author {
+                    first: String::new(),
+                    last: author.to_owned(),
+                },
+            },
+        })
+        .collect()
+}
+
+pub(crate) fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn join_authors(authors: &[Author]) -> String {
+    match authors {
+        [] => String::new(),
+        [single] => single.to_string(),
+        [init @ .., last] => format!("{} and {}", init.iter().join(", "), last),
+    }
+}
+
+/// Family names as used for short references, `Smith et al.`
+fn short_authors(entry: &BibEntry) -> String {
+    match &entry.authors[..] {
+        [] => entry.key.clone(),
+        [single] => single.last.clone(),
+        [first, second] => format!("{} and {}", first.last, second.last),
+        [first, ..] => format!("{} et al.", first.last),
+    }
+}
+
+fn alpha_label(entry: &BibEntry) -> String {
+    let mut label = match &entry.authors[..] {
+        [] => entry.key.chars().take(3).collect::<String>(),
+        [single] => single
+            .last
+            .chars()
+            .filter(|c| c.is_alphabetic())
+            .take(3)
+            .collect(),
+        authors => {
+            let initials = authors
+                .iter()
+                .take(if authors.len() > 4 { 3 } else { 4 })
+                .filter_map(|author| author.last.chars().find(|c| c.is_alphabetic()));
+            let mut initials = String::from_iter(initials);
+            if authors.len() > 4 {
+                initials.push('+');
+            }
+            initials
+        }
+    };
+    if let Some(ref year) = entry.year {
+        let digits = Vec::from_iter(year.chars().filter(char::is_ascii_digit));
+        label.extend(&digits[digits.len().saturating_sub(2)..]);
+    }
+    label
+}
+
+/// Tracks citations and assigns their labels according to a [`CitationStyle`].
+#[derive(Debug, Default)]
+pub struct Citations {
+    style: CitationStyle,
+    entries: Vec<BibEntry>,
+    /// Index into `entries` by citation key
+    by_key: HashMap<String, usize>,
+    /// Labels which are independent of the citation order, disambiguated among the listed
+    /// entries
+    fixed_labels: Vec<String>,
+    /// Citation number of cited entries by index, in order of their first citation
+    cited: HashMap<usize, usize>,
+    /// Entries to list, even though they are not cited
+    nocited: HashSet<usize>,
+    /// List all entries, as with `\nocite{*}`
+    nocite_all: bool,
+}
+
+impl Citations {
+    /// Keys in `nocite` are listed in the bibliography without being cited, `*` lists all.
+    pub fn new(style: CitationStyle, entries: Vec<BibEntry>, nocite: &[String]) -> Self {
+        let by_key = HashMap::from_iter(
+            entries
+                .iter()
+                .enumerate()
+                .map(|(idx, entry)| (entry.key.clone(), idx)),
+        );
+        let mut citations = Self {
+            style,
+            entries,
+            by_key,
+            fixed_labels: Vec::new(),
+            cited: HashMap::new(),
+            nocited: HashSet::new(),
+            nocite_all: false,
+        };
+        for key in nocite {
+            if citations.nocite(key).is_none() {
+                eprintln!("Warning: `nocite` key `{key}` is not in the bibliography");
+            }
+        }
+        citations.fixed_labels = citations.disambiguated_labels();
+        citations
+    }
+
+    /// Labels for alpha and author-year, ambiguous ones among the listed entries get a
+    /// suffix `a`, `b`, ..
+    fn disambiguated_labels(&self) -> Vec<String> {
+        let base = |entry: &BibEntry| match self.style {
+            CitationStyle::Alpha => alpha_label(entry),
+            CitationStyle::AuthorYear => format!(
+                "{}, {}",
+                short_authors(entry),
+                entry.year.as_deref().unwrap_or("n.d.")
+            ),
+            _ => String::new(),
+        };
+        let mut labels = Vec::from_iter(self.entries.iter().map(base));
+        if matches!(self.style, CitationStyle::Alpha | CitationStyle::AuthorYear) {
+            let mut seen = HashMap::<String, Vec<usize>>::new();
+            for idx in self.sorted_indices(&labels) {
+                if !self.is_listed(idx) {
+                    continue;
+                }
+                seen.entry(labels[idx].clone()).or_default().push(idx);
+            }
+            for indices in seen.values().filter(|indices| indices.len() > 1) {
+                for (suffix, &idx) in ('a'..='z').zip(indices.iter()) {
+                    labels[idx].push(suffix);
+                }
+            }
+        }
+        labels
+    }
+
+    fn sorted_indices(&self, labels: &[String]) -> Vec<usize> {
+        let mut indices = Vec::from_iter(0..self.entries.len());
+        match self.style {
+            CitationStyle::Alpha => {
+                indices.sort_by_cached_key(|&idx| labels[idx].to_lowercase());
+            }
+            CitationStyle::AuthorYear => indices.sort_by_cached_key(|&idx| {
+                let entry = &self.entries[idx];
+                (
+                    short_authors(entry).to_lowercase(),
+                    entry.year.clone(),
+                    entry.title.clone(),
+                )
+            }),
+            CitationStyle::Numeric | CitationStyle::Template(_) => {
+                let numbers = self.numbers();
+                indices.sort_by_key(|&idx| numbers[idx]);
+            }
+        }
+        indices
+    }
+
+    fn is_listed(&self, idx: usize) -> bool {
+        self.nocite_all || self.cited.contains_key(&idx) || self.nocited.contains(&idx)
+    }
+
+    /// Citation numbers of all entries, cited entries come first in order of citation,
+    /// then the other listed ones in file order
+    fn numbers(&self) -> Vec<usize> {
+        let mut next = self.cited.len();
+        Vec::from_iter((0..self.entries.len()).map(|idx| {
+            self.cited.get(&idx).copied().unwrap_or_else(|| {
+                if self.is_listed(idx) {
+                    next += 1;
+                }
+                next
+            })
+        }))
+    }
+
+    fn label(&self, idx: usize, number: usize) -> String {
+        let entry = &self.entries[idx];
+        match self.style {
+            CitationStyle::Numeric => format!("[{}]", number),
+            CitationStyle::Alpha => format!("[{}]", self.fixed_labels[idx]),
+            CitationStyle::AuthorYear => format!("({})", self.fixed_labels[idx]),
+            CitationStyle::Template(ref template) => {
+                CitationStyle::PLACEHOLDERS
+                    .iter()
+                    .fold(template.clone(), |label, &placeholder| {
+                        let value = match placeholder {
+                            "key" => entry.key.clone(),
+                            "number" => number.to_string(),
+                            "author" => entry
+                                .authors
+                                .first()
+                                .map(|author| author.last.clone())
+                                .unwrap_or_else(|| entry.key.clone()),
+                            "authors" => short_authors(entry),
+                            "year" => entry.year.clone().unwrap_or_default(),
+                            "title" => entry.title.clone().unwrap_or_default(),
+                            _ => unreachable!("All placeholders are covered. qed"),
+                        };
+                        label.replace(&format!("{{{placeholder}}}"), &value)
+                    })
+            }
+        }
+    }
+
+    /// Cite the entry with the given key and obtain its label.
+    pub fn cite(&mut self, key: &str) -> Option<String> {
+        let idx = *self.by_key.get(key)?;
+        let listed = self.is_listed(idx);
+        let next = self.cited.len() + 1;
+        let number = *self.cited.entry(idx).or_insert(next);
+        if !listed {
+            self.fixed_labels = self.disambiguated_labels();
+        }
+        Some(self.label(idx, number))
+    }
+
+    /// List the entry in the bibliography without citing it, `*` lists all entries.
+    pub fn nocite(&mut self, key: &str) -> Option<()> {
+        if key == "*" {
+            self.nocite_all = true;
+        } else {
+            self.nocited.insert(*self.by_key.get(key)?);
+        }
+        self.fixed_labels = self.disambiguated_labels();
+        Some(())
+    }
+
+    /// Keys of all entries which are neither cited nor explicitly listed, in file order.
+    pub fn uncited(&self) -> impl Iterator<Item = &str> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter(|&(idx, _)| !self.is_listed(idx))
+            .map(|(_, entry)| entry.key.as_str())
+    }
+
+    /// Render the cited and explicitly listed entries, in the order defined by the citation style.
+    pub fn render_html(&self) -> String {
+        let labels = Vec::from_iter(
+            self.numbers()
+                .into_iter()
+                .enumerate()
+                .map(|(idx, number)| self.label(idx, number)),
+        );
+        render_html(
+            self.sorted_indices(&self.fixed_labels)
+                .into_iter()
+                .filter(|&idx| self.is_listed(idx))
+                .map(|idx| (labels[idx].as_str(), &self.entries[idx])),
+        )
+    }
+}
+
+/// Render the given entries with their labels as html description list.
+///
+/// Every entry is anchored by its citation key.
+fn render_html<'a>(entries: impl IntoIterator<Item = (&'a str, &'a BibEntry)>) -> String {
+    let mut buf = String::from("<dl class=\"bibliography\">\n");
+    for (label, entry) in entries {
+        let _ = writeln!(
+            buf,
+            r#"<dt><a id="{key}">{label}</a></dt>"#,
+            key = escape_html(&entry.key),
+            label = escape_html(label),
+        );
+
+        let mut parts = Vec::new();
+        if !entry.authors.is_empty() {
+            parts.push(escape_html(&join_authors(&entry.authors)));
+        }
+        if let Some(ref title) = entry.title {
+            parts.push(format!("<em>{}</em>", escape_html(title)));
+        }
+        if let Some(ref venue) = entry.venue {
+            parts.push(escape_html(venue));
+        }
+        if let Some(ref year) = entry.year {
+            parts.push(escape_html(year));
+        }
+        let mut dd = parts.join(". ");
+        if !dd.is_empty() {
+            dd.push('.');
+        }
+        if let Some(ref doi) = entry.doi {
+            let _ = write!(
+                dd,
+                r#" <a href="https://doi.org/{doi}">doi:{doi}</a>"#,
+                doi = escape_html(doi)
+            );
+        }
+        if let Some(ref url) = entry.url {
+            let _ = write!(dd, r#" <a href="{url}">{url}</a>"#, url = escape_html(url));
+        }
+        let _ = writeln!(buf, "<dd>{}</dd>", dd.trim());
+    }
+    buf.push_str("</dl>\n");
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BIB: &str = r#"
+@article{einstein1905,
+    author = {Einstein, Albert},
+    title = {Zur {E}lektrodynamik bewegter K{\"o}rper},
+    journal = {Annalen der Physik},
+    year = 1905,
+    doi = {10.1002/andp.19053221004},
+}
+
+@book{knuth,
+    author = "Donald E. Knuth and Leslie Lamport",
+    title = "Typesetting \& more",
+    publisher = "Addison--Wesley",
+    year = "1984",
+}
+"#;
+
+    fn entries() -> Vec<BibEntry> {
+        Bibtex::parse(BIB)
+            .unwrap()
+            .bibliographies()
+            .iter()
+            .map(BibEntry::from_bibtex)
+            .collect()
+    }
+
+    #[test]
+    fn parse_entries() {
+        let entries = entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].key, "einstein1905");
+        assert_eq!(entries[0].authors[0].to_string(), "Albert Einstein");
+        assert_eq!(entries[1].authors[1].last, "Lamport");
+        assert_eq!(entries[0].year.as_deref(), Some("1905"));
+        assert_eq!(entries[1].kind, "book");
+        assert_eq!(entries[1].title.as_deref(), Some("Typesetting & more"));
+        assert_eq!(entries[1].venue.as_deref(), Some("Addison–Wesley"));
+    }
+
+    #[test]
+    fn strip_commands() {
+        assert_eq!(
+            strip_tex(r"On \emph{moving} \textit{bodies} and \textbf{{E}nergy}"),
+            "On moving bodies and Energy"
+        );
+        assert_eq!(strip_tex(r"The \LaTeX\ companion, 50\%"), "The LaTeX companion, 50%");
+    }
+
+    #[test]
+    fn render_anchors_and_escapes() {
+        let citations = Citations::new(CitationStyle::Numeric, entries(), &["*".to_owned()]);
+        let html = citations.render_html();
+        assert!(html.contains(r#"<dt><a id="einstein1905">[1]</a></dt>"#));
+        assert!(html.contains(r#"<a href="https://doi.org/10.1002/andp.19053221004">"#));
+        assert!(
+            html.contains("Donald E. Knuth and Leslie Lamport. <em>Typesetting &amp; more</em>")
+        );
+    }
+
+    #[test]
+    fn numeric_in_order_of_citation() {
+        let mut citations = Citations::new(CitationStyle::Numeric, entries(), &[]);
+        assert_eq!(citations.cite("knuth").as_deref(), Some("[1]"));
+        assert_eq!(citations.cite("einstein1905").as_deref(), Some("[2]"));
+        assert_eq!(citations.cite("knuth").as_deref(), Some("[1]"));
+        assert_eq!(citations.cite("unknown"), None);
+        let html = citations.render_html();
+        assert!(html.find(r#"id="knuth""#) < html.find(r#"id="einstein1905""#));
+    }
+
+    #[test]
+    fn alpha_and_author_year() {
+        let mut citations = Citations::new(CitationStyle::Alpha, entries(), &[]);
+        assert_eq!(citations.cite("einstein1905").as_deref(), Some("[Ein05]"));
+        assert_eq!(citations.cite("knuth").as_deref(), Some("[KL84]"));
+
+        let mut citations = Citations::new(CitationStyle::AuthorYear, entries(), &[]);
+        assert_eq!(
+            citations.cite("knuth").as_deref(),
+            Some("(Knuth and Lamport, 1984)")
+        );
+        citations.cite("einstein1905");
+        assert!(citations.render_html().contains("(Einstein, 1905)</a>"));
+    }
+
+    #[test]
+    fn disambiguate_alpha() {
+        let mut entries = entries();
+        let mut second = entries[0].clone();
+        second.key = "einstein1905b".to_owned();
+        second.title = Some("Über einen die Erzeugung".to_owned());
+        entries.push(second);
+        let mut citations = Citations::new(CitationStyle::Alpha, entries, &[]);
+        // all citations are collected before any label is emitted
+        citations.cite("einstein1905");
+        citations.cite("einstein1905b");
+        assert_eq!(citations.cite("einstein1905").as_deref(), Some("[Ein05a]"));
+        assert_eq!(citations.cite("einstein1905b").as_deref(), Some("[Ein05b]"));
+    }
+
+    #[test]
+    fn disambiguate_listed_only() {
+        let mut entries = entries();
+        let mut second = entries[0].clone();
+        second.key = "einstein1905b".to_owned();
+        entries.push(second);
+
+        let mut citations = Citations::new(CitationStyle::Alpha, entries.clone(), &[]);
+        assert_eq!(citations.cite("einstein1905").as_deref(), Some("[Ein05]"));
+        assert!(citations.render_html().contains("[Ein05]</a>"));
+
+        let mut citations = Citations::new(CitationStyle::AuthorYear, entries, &[]);
+        assert_eq!(
+            citations.cite("einstein1905").as_deref(),
+            Some("(Einstein, 1905)")
+        );
+        citations.nocite("einstein1905b");
+        assert_eq!(
+            citations.cite("einstein1905").as_deref(),
+            Some("(Einstein, 1905a)")
+        );
+        assert!(citations.render_html().contains("(Einstein, 1905b)</a>"));
+    }
+
+    #[test]
+    fn template() {
+        let style = CitationStyle::try_from("[{author} {year}, #{number}]".to_owned()).unwrap();
+        let mut citations = Citations::new(style, entries(), &[]);
+        assert_eq!(
+            citations.cite("einstein1905").as_deref(),
+            Some("[Einstein 1905, #1]")
+        );
+        assert!(CitationStyle::try_from("{nope}".to_owned()).is_err());
+        assert!(CitationStyle::try_from("chicago".to_owned()).is_err());
+    }
+
+    #[test]
+    fn only_cited_and_nocited_are_listed() {
+        let mut entries = entries();
+        let mut third = entries[0].clone();
+        third.key = "unused".to_owned();
+        entries.push(third);
+
+        let mut citations = Citations::new(CitationStyle::Numeric, entries, &["knuth".to_owned()]);
+        assert_eq!(citations.cite("einstein1905").as_deref(), Some("[1]"));
+        assert_eq!(Vec::from_iter(citations.uncited()), vec!["unused"]);
+
+        let html = citations.render_html();
+        assert!(html.contains(r#"<a id="einstein1905">[1]</a>"#));
+        assert!(html.contains(r#"<a id="knuth">[2]</a>"#));
+        assert!(!html.contains(r#"id="unused""#));
+
+        assert_eq!(citations.nocite("nope"), None);
+        assert_eq!(citations.nocite("*"), Some(()));
+        assert_eq!(citations.uncited().count(), 0);
+    }
+}
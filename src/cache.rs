@@ -0,0 +1,481 @@
+//! Manifest of the rendered fragments in the fragment directory.
+//!
+//! A fragment is named by the hash of everything its svg depends on, that is the content,
+//! the template, the zoom, the rendering pipeline and the versions of the tools involved.
+//! An svg is only reused if the manifest has an entry for it and its digest still matches,
+//! so outputs of crashed or interrupted runs are never picked up.
+//!
+//! Every build records the fragments it used, everything else can be collected as garbage.
+//! Copies in the assets directory are only collected if a build made them.
+
+use fs_err as fs;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Mutex, OnceLock};
+
+use crate::errors::*;
+use crate::fragments::{hash, HASH_LEN};
+use crate::types::Metrics;
+
+/// File name of the manifest within the fragment directory
+pub const MANIFEST: &str = "manifest.json";
+
+/// Bump whenever the naming of fragments changes
+const MANIFEST_VERSION: u32 = 2;
+
+/// Tools whose version is part of the names of the fragments using them
+const TOOLS: &[&str] = &["latex", "dvisvgm", "gnuplot"];
+
+/// Extensions of all files created while rendering a fragment
+const EXTENSIONS: &[&str] = &["tex", "dvi", "aux", "log", "svg"];
+
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+struct Manifest {
+    version: u32,
+    /// Rendered fragments by name
+    entries: BTreeMap<String, Entry>,
+    /// File names of the fragments used by the latest build, by renderer
+    #[serde(default)]
+    used: BTreeMap<String, BTreeSet<String>>,
+    /// File names of all fragments ever copied into the assets directory
+    #[serde(default)]
+    copied: BTreeSet<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+struct Entry {
+    /// Digest of the rendered svg
+    digest: String,
+    /// Baseline of inline equations
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metrics: Option<Metrics>,
+}
+
+/// Validated cache of rendered fragments, shared by all render jobs
+#[derive(Debug)]
+pub struct Cache {
+    dir: PathBuf,
+    /// First line of `--version` of each of the [`TOOLS`], looked up once a fragment uses it
+    versions: BTreeMap<&'static str, OnceLock<String>>,
+    manifest: Mutex<Manifest>,
+}
+
+impl Cache {
+    /// Open the cache in `dir`, an unreadable manifest starts an empty one.
+    pub fn open(dir: &Path) -> Self {
+        let versions = BTreeMap::from_iter(TOOLS.iter().map(|&tool| (tool, OnceLock::new())));
+        Self::with_versions(dir, versions)
+    }
+
+    /// Open the cache in `dir` with the version `toolchain` for all tools
+    fn with_toolchain(dir: &Path, toolchain: String) -> Self {
+        let versions = BTreeMap::from_iter(
+            TOOLS
+                .iter()
+                .map(|&tool| (tool, OnceLock::from(toolchain.clone()))),
+        );
+        Self::with_versions(dir, versions)
+    }
+
+    fn with_versions(dir: &Path, versions: BTreeMap<&'static str, OnceLock<String>>) -> Self {
+        let manifest = match fs::read_to_string(dir.join(MANIFEST)) {
+            Ok(manifest) => match serde_json::from_str::<Manifest>(&manifest) {
+                Ok(manifest) if manifest.version == MANIFEST_VERSION => manifest,
+                Ok(_) => Manifest::default(),
+                Err(err) => {
+                    eprintln!("Warning: Ignoring corrupt fragment manifest: {}", err);
+                    Manifest::default()
+                }
+            },
+            Err(_) => Manifest::default(),
+        };
+        Self {
+            dir: dir.to_owned(),
+            versions,
+            manifest: Mutex::new(manifest),
+        }
+    }
+
+    /// Version of `tool`, which is one of the [`TOOLS`]
+    fn version(&self, tool: &str) -> &str {
+        self.versions
+            .get(tool)
+            .expect("Only versions of `TOOLS` are looked up. qed")
+            .get_or_init(|| tool_version(tool))
+    }
+
+    /// Name of a fragment, derived from all of its inputs and the versions of the `tools`
+    /// rendering it
+    pub fn name(&self, tools: &[&str], inputs: &[&str]) -> String {
+        let mut key = MANIFEST_VERSION.to_string();
+        for version in tools.iter().map(|tool| self.version(tool)).chain(inputs.iter().copied()) {
+            key.push('\0');
+            key.push_str(version);
+        }
+        hash(key)
+    }
+
+    /// Whether the svg of `name` was completely rendered and is unchanged since.
+    pub fn is_valid(&self, name: &str) -> bool {
+        let manifest = self
+            .manifest
+            .lock()
+            .expect("Cache lock is never poisoned. qed");
+        let Some(entry) = manifest.entries.get(name) else {
+            return false;
+        };
+        match fs::read(self.dir.join(name).with_extension("svg")) {
+            Ok(svg) => hash(String::from_utf8_lossy(&svg)) == entry.digest,
+            Err(_) => false,
+        }
+    }
+
+    /// Prepare rendering of `name`, returns `true` if the cached svg can be reused.
+    ///
+    /// Otherwise all leftovers of previous attempts are removed.
+    pub fn prepare(&self, name: &str) -> Result<bool> {
+        if self.is_valid(name) {
+            return Ok(true);
+        }
+        self.manifest
+            .lock()
+            .expect("Cache lock is never poisoned. qed")
+            .entries
+            .remove(name);
+        for extension in EXTENSIONS {
+            let path = self.dir.join(name).with_extension(extension);
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(false)
+    }
+
+    /// Record the freshly rendered svg of `name`, along with its metrics if any
+    pub fn record(&self, name: &str, metrics: Option<Metrics>) -> Result<()> {
+        let svg = fs::read(self.dir.join(name).with_extension("svg"))?;
+        let entry = Entry {
+            digest: hash(String::from_utf8_lossy(&svg)),
+            metrics,
+        };
+        self.manifest
+            .lock()
+            .expect("Cache lock is never poisoned. qed")
+            .entries
+            .insert(name.to_owned(), entry);
+        Ok(())
+    }
+
+    /// Metrics recorded along with the svg of `name`
+    pub fn metrics(&self, name: &str) -> Option<Metrics> {
+        self.manifest
+            .lock()
+            .expect("Cache lock is never poisoned. qed")
+            .entries
+            .get(name)
+            .and_then(|entry| entry.metrics)
+    }
+
+    /// Record the fragments used by the current build for `renderer`, which are copied into
+    /// the assets directory
+    pub fn set_used<'a>(&self, renderer: &str, fragments: impl IntoIterator<Item = &'a PathBuf>) {
+        let used = BTreeSet::from_iter(
+            fragments
+                .into_iter()
+                .filter_map(|fragment| fragment.file_name())
+                .map(|name| name.to_string_lossy().into_owned()),
+        );
+        let mut manifest = self
+            .manifest
+            .lock()
+            .expect("Cache lock is never poisoned. qed");
+        manifest.copied.extend(used.iter().cloned());
+        manifest.used.insert(renderer.to_owned(), used);
+    }
+
+    /// Write the manifest, atomically replacing the previous one
+    pub fn save(&self) -> Result<()> {
+        let manifest = self
+            .manifest
+            .lock()
+            .expect("Cache lock is never poisoned. qed");
+        let manifest = Manifest {
+            version: MANIFEST_VERSION,
+            entries: manifest.entries.clone(),
+            used: manifest.used.clone(),
+            copied: manifest.copied.clone(),
+        };
+        let path = self.dir.join(MANIFEST);
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(&manifest)?)?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+}
+
+/// A file no build uses anymore
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Garbage {
+    pub path: PathBuf,
+    /// Size in bytes
+    pub size: u64,
+}
+
+/// Name of the fragment the file `stem` in the fragment directory belongs to, `None` for
+/// files this tool does not create.
+///
+/// Besides its own files, a gnuplot fragment includes the graphic `<name>-inc.eps`.
+fn fragment_of(stem: &str) -> Option<&str> {
+    let name = stem.strip_suffix("-inc").unwrap_or(stem);
+    let is_hash = name.len() == HASH_LEN
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b));
+    is_hash.then_some(name)
+}
+
+/// Whether `stem` is an intermediate file of a batch, `batch-<hash>` or one of its pages
+fn is_batch(stem: &str) -> bool {
+    stem.strip_prefix("batch-")
+        .and_then(|rest| rest.get(..HASH_LEN))
+        .and_then(fragment_of)
+        .is_some()
+}
+
+/// Files in `fragment_path` and copies in `assets_path` which the latest builds did not use.
+///
+/// Only files named by this tool are considered, the fragment directory may be shared.
+/// Intermediate LaTeX files are garbage even for used fragments, only the svg, the tex
+/// source, which the LaTeX renderers include, and the graphics included by the latter are
+/// kept. Within `assets_path` only files a build copied there are considered, anything else
+/// belongs to the author.
+pub fn collect_garbage(fragment_path: &Path, assets_path: &Path) -> Result<Vec<Garbage>> {
+    let cache = Cache::with_toolchain(fragment_path, String::new());
+    let manifest = cache
+        .manifest
+        .into_inner()
+        .expect("Cache lock is never poisoned. qed");
+    if manifest.used.is_empty() {
+        return Err(Error::NoBuildRecorded(fragment_path.to_owned()));
+    }
+    let used = HashSet::<&str>::from_iter(manifest.used.values().flatten().map(String::as_str));
+
+    let mut garbage = Vec::new();
+    let mut collect = |dir: &Path, is_garbage: &dyn Fn(&str) -> bool| -> Result<()> {
+        if !dir.exists() {
+            return Ok(());
+        }
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            let name = entry.file_name();
+            if metadata.is_file() && is_garbage(&name.to_string_lossy()) {
+                garbage.push(Garbage {
+                    path: entry.path(),
+                    size: metadata.len(),
+                });
+            }
+        }
+        Ok(())
+    };
+
+    collect(fragment_path, &|name| {
+        let (stem, extension) = name.split_once('.').unwrap_or((name, ""));
+        if is_batch(stem) {
+            return true;
+        }
+        let Some(fragment) = fragment_of(stem) else {
+            return false;
+        };
+        let is_included = stem != fragment || ["svg", "tex"].contains(&extension);
+        !(used.contains(format!("{}.svg", fragment).as_str()) && is_included)
+    })?;
+    collect(assets_path, &|name| {
+        manifest.copied.contains(name) && !used.contains(name)
+    })?;
+
+    garbage.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(garbage)
+}
+
+/// Remove the collected garbage and drop the removed fragments and copies from the manifest
+pub fn remove_garbage(fragment_path: &Path, assets_path: &Path, garbage: &[Garbage]) -> Result<()> {
+    for file in garbage {
+        fs::remove_file(&file.path)?;
+    }
+
+    let cache = Cache::with_toolchain(fragment_path, String::new());
+    let mut manifest = cache
+        .manifest
+        .lock()
+        .expect("Cache lock is never poisoned. qed");
+    manifest
+        .entries
+        .retain(|name, _| fragment_path.join(name).with_extension("svg").exists());
+    manifest
+        .copied
+        .retain(|name| assets_path.join(name).exists());
+    drop(manifest);
+    cache.save()
+}
+
+/// First line of `<tool> --version`, empty if the tool is not available
+fn tool_version(tool: &str) -> String {
+    which::which(tool)
+        .ok()
+        .and_then(|path| Command::new(path).arg("--version").output().ok())
+        .and_then(|output| {
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .next()
+                .map(str::to_owned)
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn names_depend_on_all_inputs() {
+        let cache = Cache::with_toolchain(&dir("mdbook-scientific-cache-names"), "tex 1".into());
+        let name = cache.name(&["latex"], &["equation", "template", "1.3", "x"]);
+        assert_eq!(name, cache.name(&["latex"], &["equation", "template", "1.3", "x"]));
+        assert_ne!(name, cache.name(&["latex"], &["equation", "template", "1.6", "x"]));
+        assert_ne!(name, cache.name(&["latex"], &["equation", "template2", "1.3", "x"]));
+
+        let other = Cache::with_toolchain(&dir("mdbook-scientific-cache-names"), "tex 2".into());
+        assert_ne!(name, other.name(&["latex"], &["equation", "template", "1.3", "x"]));
+        assert_eq!(
+            cache.name(&[], &["equation", "template", "1.3", "x"]),
+            other.name(&[], &["equation", "template", "1.3", "x"])
+        );
+    }
+
+    #[test]
+    fn versions_of_used_tools_only() {
+        let cache = Cache::open(&dir("mdbook-scientific-cache-versions"));
+        assert!(cache.versions.values().all(|version| version.get().is_none()));
+        cache.name(&["gnuplot"], &["x"]);
+        let looked_up = Vec::from_iter(
+            cache
+                .versions
+                .iter()
+                .filter(|(_, version)| version.get().is_some())
+                .map(|(tool, _)| *tool),
+        );
+        assert_eq!(looked_up, ["gnuplot"]);
+    }
+
+    #[test]
+    fn validate_record_and_reload() {
+        let dir = dir("mdbook-scientific-cache-validate");
+        let cache = Cache::with_toolchain(&dir, String::new());
+
+        // leftovers of an interrupted run are never reused
+        fs::write(dir.join("a.svg"), "<svg>").unwrap();
+        fs::write(dir.join("a.dvi"), "garbage").unwrap();
+        assert!(!cache.prepare("a").unwrap());
+        assert!(!dir.join("a.svg").exists());
+        assert!(!dir.join("a.dvi").exists());
+
+        fs::write(dir.join("a.svg"), "<svg/>").unwrap();
+        cache.record("a", None).unwrap();
+        assert!(cache.prepare("a").unwrap());
+        cache.save().unwrap();
+        assert!(!dir.join("manifest.json.tmp").exists());
+
+        let cache = Cache::with_toolchain(&dir, String::new());
+        assert!(cache.is_valid("a"));
+
+        // corrupted after rendering
+        fs::write(dir.join("a.svg"), "<sv").unwrap();
+        assert!(!cache.is_valid("a"));
+    }
+
+    #[test]
+    fn garbage() {
+        let fragments = dir("mdbook-scientific-gc-fragments");
+        let assets = dir("mdbook-scientific-gc-assets");
+        let cache = Cache::with_toolchain(&fragments, String::new());
+        // an equation `a`, a gnuplot figure `g` including its graphic and a stale one `b`
+        let [a, b, g] = [["a"], ["b"], ["g"]].map(|inputs| cache.name(&[], &inputs));
+        let batch = format!("batch-{}", hash("c"));
+        let file = |name: &str, extension: &str| format!("{}{}", name, extension);
+        for name in [
+            file(&a, ".svg"),
+            file(&a, ".tex"),
+            file(&a, ".dvi"),
+            file(&a, ".log"),
+            file(&b, ".svg"),
+            file(&b, ".tex"),
+            file(&b, "-inc.eps"),
+            file(&g, ".svg"),
+            file(&g, ".tex"),
+            file(&g, ".dvi"),
+            file(&g, "-inc.eps"),
+            file(&batch, ".tex"),
+            file(&batch, "-1.svg"),
+            // the fragment directory is shared with the author
+            "notes.txt".to_owned(),
+            "plot.svg".to_owned(),
+        ] {
+            fs::write(fragments.join(name), "x").unwrap();
+        }
+        // `figure.svg` was put there by the author
+        for name in [file(&a, ".svg"), file(&b, ".svg"), "figure.svg".to_owned()] {
+            fs::write(assets.join(name), "x").unwrap();
+        }
+
+        assert_matches::assert_matches!(
+            collect_garbage(&fragments, &assets),
+            Err(Error::NoBuildRecorded(_))
+        );
+
+        for name in [&a, &b, &g] {
+            cache.record(name, None).unwrap();
+        }
+        let svg = |name: &str| PathBuf::from(file(name, ".svg"));
+        cache.set_used("html", &[svg(&a), svg(&b)]);
+        cache.set_used("html", &[svg(&a), svg(&g)]);
+        cache.save().unwrap();
+
+        let garbage = collect_garbage(&fragments, &assets).unwrap();
+        let names = Vec::from_iter(garbage.iter().map(|file| file.path.clone()));
+        let mut expected = vec![
+            assets.join(file(&b, ".svg")),
+            fragments.join(file(&a, ".dvi")),
+            fragments.join(file(&a, ".log")),
+            fragments.join(file(&b, ".svg")),
+            fragments.join(file(&b, ".tex")),
+            fragments.join(file(&b, "-inc.eps")),
+            fragments.join(file(&g, ".dvi")),
+            fragments.join(file(&batch, ".tex")),
+            fragments.join(file(&batch, "-1.svg")),
+        ];
+        expected.sort();
+        assert_eq!(names, expected);
+        assert_eq!(garbage.iter().map(|file| file.size).sum::<u64>(), 9);
+
+        remove_garbage(&fragments, &assets, &garbage).unwrap();
+        assert!(collect_garbage(&fragments, &assets).unwrap().is_empty());
+        for kept in ["notes.txt", "plot.svg"] {
+            assert!(fragments.join(kept).exists());
+        }
+        assert!(fragments.join(file(&g, "-inc.eps")).exists());
+        assert!(assets.join("figure.svg").exists());
+        assert!(assets.join(file(&a, ".svg")).exists());
+        let cache = Cache::with_toolchain(&fragments, String::new());
+        assert!(cache.is_valid(&a));
+        assert!(!cache.is_valid(&b));
+    }
+}
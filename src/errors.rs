@@ -1,4 +1,9 @@
+use itertools::Itertools;
 use nom_bibtex::error::BibtexError;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::types::LiCo;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -13,17 +18,30 @@ pub enum Error {
     #[error("Rendered `{0}` not supported")]
     RendererNotSupported(String),
 
-    #[error("Invalid math: {0} {1} at line {2}")]
-    InvalidMath(String, String, usize),
+    #[error("Invalid math: {message} {context}{location}")]
+    InvalidMath {
+        message: String,
+        context: String,
+        location: Location,
+    },
+
+    #[error("Invalid reference to `{to}`{location}")]
+    InvalidReference { to: String, location: Location },
 
-    #[error("Invalid reference to `{to}` in line no. {lineno}")]
-    InvalidReference { to: String, lineno: usize },
+    #[error("Unknown reference to `{kind}`{location}")]
+    UnknownReferenceKind { kind: String, location: Location },
 
-    #[error("Unknown reference to `{kind}` in line no. {lineno}")]
-    UnknownReferenceKind { kind: String, lineno: usize },
+    #[error("Got `{count}` arguements{location}")]
+    UnexpectedReferenceArgCount { count: usize, location: Location },
 
-    #[error("Got `{count}` arguements in line no. {lineno}")]
-    UnexpectedReferenceArgCount { count: usize, lineno: usize },
+    #[error("Invalid block header: {reason}{location}")]
+    InvalidBlockHeader { reason: String, location: Location },
+
+    #[error("Unknown preamble profile `{name}`{location}")]
+    UnknownPreamble { name: String, location: Location },
+
+    #[error("Label `{name}` is defined more than once{location}")]
+    DuplicateLabel { name: String, location: Location },
 
     #[error("Invalid bibliography: {0}")]
     InvalidBibliography(String),
@@ -31,6 +49,20 @@ pub enum Error {
     #[error("Invalid dvi svgm: {0}")]
     InvalidDvisvgm(String),
 
+    #[error("Failed to run `{command}`: {error}")]
+    CommandIo {
+        command: String,
+        #[source]
+        error: std::io::Error,
+    },
+
+    #[error("`{command}` failed with {status}:\n{}", .stderr.trim_end())]
+    CommandFailed {
+        command: String,
+        status: std::process::ExitStatus,
+        stderr: String,
+    },
+
     #[error("Binary \"{binary}\" was not found using `which`")]
     BinaryNotFound {
         binary: String,
@@ -44,6 +76,12 @@ pub enum Error {
     #[error("Key section not found")]
     KeySectionNotFound,
 
+    #[error("Invalid configuration key `{key}`: {reason}")]
+    InvalidConfig { key: String, reason: String },
+
+    #[error("No build is recorded in `{0}`, build the book first")]
+    NoBuildRecorded(std::path::PathBuf),
+
     #[error(transparent)]
     Io(#[from] std::io::Error),
 
@@ -61,4 +99,109 @@ pub enum Error {
 
     #[error(transparent)]
     Which(#[from] which::Error),
+
+    #[error("{} errors occurred:\n\n{}", .0.len(), .0.iter().join("\n\n"))]
+    Multiple(Vec<Error>),
+
+    #[error(transparent)]
+    Shared(std::sync::Arc<Error>),
+}
+
+impl Error {
+    /// Fail with all `errors` of the book at once, unless there are none
+    pub fn all(mut errors: Vec<Error>) -> Result<()> {
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.remove(0)),
+            _ => Err(Error::Multiple(errors)),
+        }
+    }
+
+    /// Attribute a located error to the chapter at `path` with the content `source`, unless
+    /// it is known already
+    pub fn located(mut self, path: Option<&Path>, source: &str) -> Self {
+        if let Some(location) = self.location_mut() {
+            if location.path.is_none() {
+                location.path = path.map(Path::to_path_buf);
+            }
+            if location.line.is_empty() {
+                location.line = Location::new(path, source, location.lico).line;
+            }
+        }
+        self
+    }
+
+    fn location_mut(&mut self) -> Option<&mut Location> {
+        match self {
+            Self::InvalidMath { location, .. }
+            | Self::InvalidReference { location, .. }
+            | Self::UnknownReferenceKind { location, .. }
+            | Self::UnexpectedReferenceArgCount { location, .. }
+            | Self::InvalidBlockHeader { location, .. }
+            | Self::UnknownPreamble { location, .. }
+            | Self::DuplicateLabel { location, .. } => Some(location),
+            _ => None,
+        }
+    }
+}
+
+/// Location of an error or a warning within a chapter, displayed as a snippet like rustc does
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Location {
+    /// Source path of the chapter, relative to the `src` directory
+    pub path: Option<PathBuf>,
+    pub lico: LiCo,
+    /// The line at `lico`, shown with a caret below the column
+    pub line: String,
+}
+
+impl Location {
+    /// Location of `lico` within the chapter at `path` with the content `source`
+    pub fn new(path: Option<&Path>, source: &str, lico: LiCo) -> Self {
+        Self {
+            path: path.map(Path::to_path_buf),
+            lico,
+            line: source
+                .lines()
+                .nth(lico.lineno.saturating_sub(1))
+                .unwrap_or_default()
+                .to_owned(),
+        }
+    }
+
+    /// Location of `lico` within a chapter which is filled in later, see [`Error::located`]
+    pub fn at(lico: LiCo) -> Self {
+        Self {
+            lico,
+            ..Default::default()
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let LiCo { lineno, column } = self.lico;
+        let gutter = " ".repeat(lineno.to_string().len());
+        match self.path {
+            Some(ref path) => write!(f, "\n{gutter}--> {}:{lineno}:{column}", path.display())?,
+            None => write!(f, "\n{gutter}--> line {lineno}, column {column}")?,
+        }
+        if !self.line.trim().is_empty() {
+            // keep tabs, so the caret lines up
+            let indent =
+                String::from_iter(self.line.chars().take(column.saturating_sub(1)).map(|c| {
+                    if c == '\t' {
+                        '\t'
+                    } else {
+                        ' '
+                    }
+                }));
+            write!(
+                f,
+                "\n{gutter} |\n{lineno} | {}\n{gutter} | {indent}^",
+                self.line
+            )?;
+        }
+        Ok(())
+    }
 }
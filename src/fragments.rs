@@ -1,109 +1,504 @@
 use fs_err as fs;
-use itertools::Itertools;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
-use std::{io::Write, str, usize};
+use std::process::{Command, Output, Stdio};
+use std::{io::Write, str};
+
+use itertools::Itertools;
 
 use sha2::{Digest, Sha256};
 
 use crate::errors::*;
 use crate::types::*;
 
+/// Number of hex digits of a [`hash`]
+pub const HASH_LEN: usize = 24;
+
 /// Convert input string to 24 character hash
 pub fn hash(input: impl AsRef<str>) -> String {
     let mut sh = Sha256::new();
     sh.update(input.as_ref().as_bytes());
     let mut out = format!("{:x}", sh.finalize());
-    out.truncate(24);
+    out.truncate(HASH_LEN);
     out
 }
 
-fn find_binary(name: &str) -> Result<std::path::PathBuf> {
+/// Path of the binary `name`, the error names the missing one
+pub(crate) fn find_binary(name: &str) -> Result<std::path::PathBuf> {
     which::which(name).map_err(|error| Error::BinaryNotFound {
         binary: name.to_owned(),
         error,
     })
 }
 
+/// The command line of `cmd`, for error messages
+fn command_line(cmd: &Command) -> String {
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| {
+            let arg = arg.to_string_lossy();
+            if arg.is_empty() || arg.contains(char::is_whitespace) {
+                format!("'{}'", arg)
+            } else {
+                arg.into_owned()
+            }
+        })
+        .join(" ")
+}
+
+/// Run `cmd` to completion with `input` on its stdin, capturing its stdout and stderr
+///
+/// The exit status is not checked, see [`failed`].
+pub(crate) fn run(cmd: &mut Command, input: Option<&[u8]>) -> Result<Output> {
+    let command = command_line(cmd);
+    let io = |error| Error::CommandIo {
+        command: command.clone(),
+        error,
+    };
+    let mut child = cmd
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(io)?;
+
+    // write concurrently, a full stdout pipe would block the command otherwise
+    let stdin = child.stdin.take();
+    let (written, output) = std::thread::scope(|scope| {
+        let writer = scope.spawn(move || match (stdin, input) {
+            // dropping stdin closes it, so the command terminates
+            (Some(mut stdin), Some(input)) => stdin.write_all(input),
+            _ => Ok(()),
+        });
+        let output = child.wait_with_output();
+        let written = writer
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+        (written, output)
+    });
+    let output = output.map_err(io)?;
+    // a command exiting early closes its stdin, which the exit status explains better
+    if output.status.success() {
+        written.map_err(io)?;
+    }
+    Ok(output)
+}
+
+/// The error of `cmd`, which exited with a failure
+pub(crate) fn failed(cmd: &Command, output: &Output) -> Error {
+    Error::CommandFailed {
+        command: command_line(cmd),
+        status: output.status,
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+    }
+}
+
+/// Template equations are rendered with, the built-in `fragment.tex` extended by the
+/// configured preamble
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    /// Everything before `\\begin{document}`, the configured preamble included
+    head: String,
+}
+
+impl Default for Template {
+    fn default() -> Self {
+        Self::new("")
+    }
+}
+
+impl Template {
+    /// The built-in `fragment.tex` split into the part before `\\begin{document}`, the one
+    /// from there up to the equation, and the one after the equation.
+    ///
+    /// The configured preamble is only ever spliced in between the parts, so whatever it
+    /// contains can't be mistaken for the slot of the equation.
+    fn builtin() -> (&'static str, &'static str, &'static str) {
+        let (head, document) = Self::builtin_document();
+        let (body, rest) = document
+            .split_once("$$")
+            .expect("fragment.tex has a `$$` slot. qed");
+        let (_, postamble) = rest
+            .split_once("$$")
+            .expect("fragment.tex has a `$$` slot. qed");
+        (head, body, postamble)
+    }
+
+    /// The built-in `fragment.tex` split at `\\begin{document}`
+    fn builtin_document() -> (&'static str, &'static str) {
+        const FRAGMENT: &str = include_str!("fragment.tex");
+        let start = FRAGMENT
+            .find("\\begin{document}")
+            .expect("fragment.tex contains `\\begin{document}`. qed");
+        FRAGMENT.split_at(start)
+    }
+
+    /// Extend the built-in template, `preamble` is inserted right before `\\begin{document}`
+    pub fn new(preamble: &str) -> Self {
+        let (head, _, _) = Self::builtin();
+        Self {
+            head: head.to_owned(),
+        }
+        .with(preamble)
+    }
+
+    /// Extend this template, `preamble` is inserted right before `\\begin{document}`
+    pub fn with(&self, preamble: &str) -> Self {
+        Self {
+            head: format!("{}{}", self.head, preamble),
+        }
+    }
+
+    /// The whole template, which is part of the name of every equation
+    pub fn source(&self) -> String {
+        let (_, document) = Self::builtin_document();
+        format!("{}{}", self.head, document)
+    }
+
+    /// Document typesetting `tex` as a displayed or as an inline equation
+    fn document(&self, tex: &str, inline: bool) -> String {
+        let (preamble, postamble) = self.parts();
+        let delimiter = if inline { "$" } else { "$$" };
+        format!("{preamble}{delimiter}{tex}{delimiter}{postamble}")
+    }
+
+    /// Parts before and after the equation
+    fn parts(&self) -> (String, &'static str) {
+        let (_, body, postamble) = Self::builtin();
+        (format!("{}{}", self.head, body), postamble)
+    }
+}
+
+/// Run `latex` on the given tex file within `dest_path`, which creates a dvi next to it
+///
+/// Errors are located in the line of the tex file, see [`locate`] for the markdown source.
+fn run_latex(dest_path: &Path, file: &Path) -> Result<()> {
+    let latex_path = find_binary("latex")?;
+
+    let mut cmd = Command::new(latex_path);
+    cmd.current_dir(dest_path)
+        //.arg("--jobname").arg(&dvi_path)
+        .arg(file);
+    let output = run(&mut cmd, None)?;
+
+    if !output.status.success() {
+        let buf = String::from_utf8_lossy(&output.stdout);
+
+        // latex prints error to the stdout, if this is empty, then something is fundamentally
+        // wrong with the latex binary (for example shared library error)
+        if buf.is_empty() {
+            return Err(failed(&cmd, &output));
+        }
+
+        let err = buf
+            .split('\n')
+            .filter(|x| {
+                (x.starts_with("! ") || x.starts_with("l.")) && !x.contains("Emergency stop")
+            })
+            .fold(("", "", usize::MAX), |mut err, elm| {
+                if let Some(striped) = elm.strip_prefix("! ") {
+                    err.0 = striped;
+                } else if let Some(striped) = elm.strip_prefix("l.") {
+                    let mut elms = striped.splitn(2, ' ').map(|x| x.trim());
+                    if let Some(Ok(val)) = elms.next().map(|x| x.parse::<usize>()) {
+                        err.2 = val;
+                    }
+                    if let Some(val) = elms.next() {
+                        err.1 = val;
+                    }
+                }
+
+                err
+            });
+
+        return Err(Error::InvalidMath {
+            message: err.0.to_string(),
+            context: err.1.to_string(),
+            location: Location::at(LiCo {
+                lineno: err.2,
+                column: 1,
+            }),
+        });
+    }
+
+    Ok(())
+}
+
+/// Locate an error of `latex` within the markdown source.
+///
+/// `content` is the source of a part of the tex file, starting in line `first_line` of it, and
+/// at `origin` within the chapter. The caret points at the end of the context `latex` reports.
+/// Errors outside of `content` are located at `origin`.
+fn locate(err: Error, origin: &Location, first_line: usize, content: &str) -> Error {
+    let Error::InvalidMath {
+        message,
+        context,
+        location,
+    } = err
+    else {
+        return err;
+    };
+    let offset = location.lico.lineno.checked_sub(first_line);
+    let location = match offset.and_then(|k| Some((k, content.split('\n').nth(k)?))) {
+        Some((k, within)) => {
+            // the first line of the content might not be the first of the markdown line
+            let (lineno, column, line) = if k == 0 {
+                (origin.lico.lineno, origin.lico.column, origin.line.clone())
+            } else {
+                (origin.lico.lineno + k, 1, within.to_owned())
+            };
+            let column = match within
+                .find(context.as_str())
+                .filter(|_| !context.is_empty())
+            {
+                Some(idx) => column + within[..idx + context.len()].chars().count() - 1,
+                None => column,
+            };
+            Location {
+                path: origin.path.clone(),
+                lico: LiCo { lineno, column },
+                line,
+            }
+        }
+        None => origin.clone(),
+    };
+    Error::InvalidMath {
+        message,
+        context,
+        location,
+    }
+}
+
+/// Bounding box of the svgs created by `dvisvgm`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BoundingBox {
+    /// Tight around the ink, with a margin of `1pt`
+    Margin,
+    /// The box typeset by `latex`, which keeps the baseline and reports the metrics
+    Preview,
+}
+
+/// Run `dvisvgm` on the given dvi file within `dest_path`, with the woff font format
+///
+/// Returns the metrics of every page, which are only known for [`BoundingBox::Preview`].
+fn run_dvisvgm(
+    dest_path: &Path,
+    dvi_path: &Path,
+    zoom: f32,
+    bbox: BoundingBox,
+    args: &[&str],
+) -> Result<Vec<Metrics>> {
+    let dvisvgm_path = find_binary("dvisvgm")?;
+
+    let mut cmd = Command::new(dvisvgm_path);
+    cmd.current_dir(dest_path)
+        .arg(match bbox {
+            BoundingBox::Margin => "--bbox=1",
+            BoundingBox::Preview => "--bbox=preview",
+        })
+        .arg("--exact-bbox")
+        .arg("--font-format=woff")
+        .arg(format!("--zoom={}", zoom))
+        .args(args)
+        .arg(dvi_path);
+    let output = run(&mut cmd, None)?;
+
+    if !output.status.success() {
+        return Err(failed(&cmd, &output));
+    }
+    let buf = String::from_utf8_lossy(&output.stderr);
+    if buf.contains("error:") {
+        return Err(Error::InvalidDvisvgm(buf.to_string()));
+    }
+
+    Ok(match bbox {
+        BoundingBox::Margin => Vec::new(),
+        BoundingBox::Preview => parse_metrics(&buf, zoom),
+    })
+}
+
+/// Metrics of all pages reported by `dvisvgm`, scaled by `zoom`
+///
+/// With the `preview` bounding box every page is reported as
+/// `width=33.1pt, height=6.94pt, depth=2.49pt`.
+fn parse_metrics(output: &str, zoom: f32) -> Vec<Metrics> {
+    let dimension = |line: &str, key: &str| -> Option<f32> {
+        let start = line.find(key)? + key.len();
+        let value = line[start..].split("pt").next()?;
+        value.trim().parse::<f32>().ok()
+    };
+    Vec::from_iter(output.lines().filter_map(|line| {
+        Some(Metrics {
+            height: dimension(line, "height=")? * zoom,
+            depth: dimension(line, "depth=")? * zoom,
+        })
+    }))
+}
+
 /// Generate SVG file from latex file with given zoom
 pub fn generate_svg_from_latex(path: &Path, zoom: f32) -> Result<()> {
+    render_svg(path, zoom, BoundingBox::Margin).map(|_| ())
+}
+
+/// Generate SVG file from latex file, returns the metrics with the preview bounding box
+fn render_svg(path: &Path, zoom: f32, bbox: BoundingBox) -> Result<Option<Metrics>> {
     let dest_path = path.parent().expect("Parent path must exist. qed");
-    let file: &Path = path.file_name().unwrap().as_ref();
+    let file: &Path = path.file_name().expect("Fragments are named. qed").as_ref();
 
     // use latex to generate a dvi
     let dvi_path = path.with_extension("dvi");
     if !dvi_path.exists() {
-        let latex_path = find_binary("latex")?;
-
-        let cmd = Command::new(latex_path)
-            .current_dir(dest_path)
-            //.arg("--jobname").arg(&dvi_path)
-            .arg(&file.with_extension("tex"))
-            .output()
-            .expect("Could not spawn latex");
-
-        if !cmd.status.success() {
-            let buf = String::from_utf8_lossy(&cmd.stdout);
-
-            // latex prints error to the stdout, if this is empty, then something is fundamentally
-            // wrong with the latex binary (for example shared library error). In this case just
-            // exit the program
-            if buf.is_empty() {
-                let buf = String::from_utf8_lossy(&cmd.stderr);
-                panic!("latex exited with `{}`", buf);
-            }
-
-            let err = buf
-                .split('\n')
-                .filter(|x| {
-                    (x.starts_with("! ") || x.starts_with("l.")) && !x.contains("Emergency stop")
-                })
-                .fold(("", "", usize::MAX), |mut err, elm| {
-                    if let Some(striped) = elm.strip_prefix("! ") {
-                        err.0 = striped;
-                    } else if let Some(striped) = elm.strip_prefix("l.") {
-                        let mut elms = striped.splitn(2, ' ').map(|x| x.trim());
-                        if let Some(Ok(val)) = elms.next().map(|x| x.parse::<usize>()) {
-                            err.2 = val;
-                        }
-                        if let Some(val) = elms.next() {
-                            err.1 = val;
-                        }
-                    }
-
-                    err
-                });
-
-            return Err(Error::InvalidMath(
-                err.0.to_string(),
-                err.1.to_string(),
-                err.2,
-            ));
-        }
+        run_latex(dest_path, &file.with_extension("tex"))?;
     }
 
     // convert the dvi to a svg file with the woff font format
     let svg_path = path.with_extension("svg");
     if !svg_path.exists() && dvi_path.exists() {
-        let dvisvgm_path = find_binary("dvisvgm")?;
-
-        let cmd = Command::new(dvisvgm_path)
-            .current_dir(dest_path)
-            .arg("-b")
-            .arg("1")
-            .arg("--font-format=woff")
-            .arg(&format!("--zoom={}", zoom))
-            .arg(&dvi_path)
-            .output()
-            .expect("Couldn't run svisvgm properly!");
-
-        let buf = String::from_utf8_lossy(&cmd.stderr);
-        if !cmd.status.success() || buf.contains("error:") {
-            return Err(Error::InvalidDvisvgm(buf.to_string()));
-        }
+        let metrics = run_dvisvgm(dest_path, &dvi_path, zoom, bbox, &[])?;
+        return Ok(metrics.first().copied());
     }
 
-    Ok(())
+    Ok(None)
+}
+
+/// Render many equations with the same zoom in a single `latex` and `dvisvgm` run
+///
+/// Every equation becomes a page of a multi page `standalone` document, the pages are then
+/// split into the same `<name>.svg` files [`generate_replacement_file_from_template`] creates.
+/// Errors are located at the source line of the offending equation.
+///
+/// Returns the metrics of every equation, if they are `inline`.
+pub fn generate_replacement_files_from_template_batch(
+    dest_path: &Path,
+    template: &Template,
+    names: &[String],
+    contents: &[Content<'_>],
+    origins: &[Location],
+    zoom: f32,
+    inline: bool,
+) -> Result<Vec<Option<Metrics>>> {
+    let batch = format!(
+        "batch-{}",
+        hash(format!("{}{}{}", names.join(""), zoom, inline))
+    );
+    let path = dest_path.join(&batch);
+
+    let maths = Vec::from_iter(contents.iter().map(Content::math));
+    let (document, first_lines) = batch_document(template, &maths, inline);
+    fs::write(path.with_extension("tex"), document)?;
+
+    run_latex(dest_path, &PathBuf::from(&batch).with_extension("tex"))
+        .map_err(|err| locate_in_batch(err, &first_lines, origins, contents))?;
+
+    let pattern = format!("--output={}-%p.svg", batch);
+    let bbox = if inline {
+        BoundingBox::Preview
+    } else {
+        BoundingBox::Margin
+    };
+    let metrics = run_dvisvgm(
+        dest_path,
+        &path.with_extension("dvi"),
+        zoom,
+        bbox,
+        &["--page=1-", &pattern],
+    )?;
+
+    let pages = batch_pages(dest_path, &batch)?;
+    if pages.len() != names.len() {
+        return Err(Error::InvalidDvisvgm(format!(
+            "Expected {} pages from `{}.dvi`, but got {}",
+            names.len(),
+            batch,
+            pages.len()
+        )));
+    }
+    for (page, name) in pages.into_iter().zip(names) {
+        fs::rename(
+            dest_path.join(page),
+            dest_path.join(name).with_extension("svg"),
+        )?;
+    }
+
+    // without the metrics of every page there is no telling which belongs to which
+    if metrics.len() == names.len() {
+        Ok(Vec::from_iter(metrics.into_iter().map(Some)))
+    } else {
+        Ok(vec![None; names.len()])
+    }
+}
+
+/// Multi page document of a batch of equations, each in its own `standalone` environment.
+///
+/// Returns the document and the line each of the `contents` starts in.
+fn batch_document(
+    template: &Template,
+    contents: &[impl AsRef<str>],
+    inline: bool,
+) -> (String, Vec<usize>) {
+    let delimiter = if inline { "$" } else { "$$" };
+
+    // each `standalone` environment becomes a page in multi mode
+    let (preamble, postamble) = template.parts();
+    let mut document = preamble.replacen("]{standalone}", ", multi]{standalone}", 1);
+    let mut lines = document.matches('\n').count();
+    let mut first_lines = Vec::with_capacity(contents.len());
+    for content in contents {
+        let content = content.as_ref();
+        document.push_str("\\begin{standalone}");
+        document.push_str(delimiter);
+        document.push('\n');
+        first_lines.push(lines + 2);
+        document.push_str(content);
+        document.push('\n');
+        document.push_str(delimiter);
+        document.push_str("\\end{standalone}\n");
+        lines += content.matches('\n').count() + 3;
+    }
+    document.push_str(postamble);
+    (document, first_lines)
+}
+
+/// Locate an error of `latex` in a batch document at the equation of the line it reports,
+/// see [`batch_document`] and [`locate`]
+fn locate_in_batch(
+    err: Error,
+    first_lines: &[usize],
+    origins: &[Location],
+    contents: &[impl AsRef<str>],
+) -> Error {
+    let line = match err {
+        Error::InvalidMath { ref location, .. } => location.lico.lineno,
+        _ => return err,
+    };
+    let idx = first_lines
+        .iter()
+        .rposition(|first| *first <= line)
+        .unwrap_or_default();
+    locate(err, &origins[idx], first_lines[idx], contents[idx].as_ref())
+}
+
+/// Svgs of the pages of `batch` within `dir` in order of their page, as split by `dvisvgm`
+fn batch_pages(dir: &Path, batch: &str) -> Result<Vec<std::ffi::OsString>> {
+    let mut pages = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let file_name = entry?.file_name();
+        // page numbers might be padded with zeros
+        let page = file_name
+            .to_str()
+            .and_then(|name| name.strip_prefix(batch)?.strip_prefix('-'))
+            .and_then(|name| name.strip_suffix(".svg")?.parse::<usize>().ok());
+        if let Some(page) = page {
+            pages.push((page, file_name));
+        }
+    }
+    pages.sort();
+    Ok(Vec::from_iter(
+        pages.into_iter().map(|(_, file_name)| file_name),
+    ))
 }
 
 /// Generate latex file from gnuplot
@@ -115,44 +510,38 @@ fn generate_latex_from_gnuplot<'a>(
     content: &Content<'a>,
     filename: &str,
 ) -> Result<()> {
-    let content = content.as_ref();
+    let content = content.math();
     let gnuplot_path = find_binary("gnuplot")?;
 
-    let cmd = Command::new(gnuplot_path)
-        .stdin(Stdio::piped())
-        .current_dir(dest_path)
-        .arg("-p")
-        .spawn()?;
-
-    let mut stdin = cmd.stdin.expect("Stdin of gnuplot spawn must exist. qed");
-
-    stdin.write_all(format!("set output '{}.tex'\n", filename).as_bytes())?;
-    stdin.write_all("set terminal epslatex color standalone\n".as_bytes())?;
-    stdin.write_all(content.as_bytes())?;
+    let mut cmd = Command::new(gnuplot_path);
+    cmd.current_dir(dest_path).arg("-p");
+    let input = format!(
+        "set output '{}.tex'\nset terminal epslatex color standalone\n{}",
+        filename, content
+    );
+    let output = run(&mut cmd, Some(input.as_bytes()))?;
+    if !output.status.success() {
+        return Err(failed(&cmd, &output));
+    }
 
     Ok(())
 }
 
 /// Parse an equation with the given zoom
+///
+/// Inline equations are typeset in text style and keep their baseline.
 pub fn generate_replacement_file_from_template<'a>(
     dest_path: &Path,
+    template: &Template,
+    name: &str,
     content: &Content<'a>,
+    origin: &Location,
     zoom: f32,
+    inline: bool,
 ) -> Result<Replacement<'a>> {
-    let name = hash(content);
-    let path = dest_path.join(&name);
-
-    eprintln!(
-        r#"Found equation from {}:{}..{}:{}:
-    {}"#,
-        content.start.lineno,
-        content.start.column,
-        content.end.lineno,
-        content.end.column,
-        content.s
-    );
+    let path = dest_path.join(name);
 
-    let tex = content.as_ref();
+    let tex = content.math();
     // create a new tex file containing the equation
     if !path.with_extension("tex").exists() {
         let mut file = fs::OpenOptions::new()
@@ -161,33 +550,40 @@ pub fn generate_replacement_file_from_template<'a>(
             .write(true)
             .open(path.with_extension("tex"))?;
 
-        let fragment = include_str!("fragment.tex")
-            .split("$$")
-            .enumerate()
-            .map(|(idx, s)| match idx {
-                0 | 2 => s,
-                1 => tex,
-                _ => unreachable!("fragment.tex must have exactly 2 instances of `$$`"),
-            })
-            .join("$$");
-
-        file.write_all(fragment.as_bytes())?;
+        file.write_all(template.document(&tex, inline).as_bytes())?;
     }
 
-    generate_svg_from_latex(&path, zoom)?;
+    let mut metrics = None;
+    if !path.with_extension("svg").exists() {
+        let bbox = if inline {
+            BoundingBox::Preview
+        } else {
+            BoundingBox::Margin
+        };
+        let (preamble, _) = template.parts();
+        let first_line = preamble.matches('\n').count() + 1;
+        metrics = render_svg(&path, zoom, bbox)
+            .map_err(|err| locate(err, origin, first_line, content.as_ref()))?;
+    }
 
     Ok(Replacement {
         content: content.clone(),
         intermediate: None,
-        svg: PathBuf::from(name + ".svg"),
+        svg: PathBuf::from(name).with_extension("svg"),
+        metrics,
     })
 }
 
-/// Parse a latex content and convert it to a SVG file
-pub fn parse_latex<'a>(dest_path: &Path, content: &Content<'a>) -> Result<Replacement<'a>> {
-    let tex = content.as_ref();
-    let name = hash(tex);
-    let path = dest_path.join(&name);
+/// Parse a latex content and convert it to a SVG file with the given zoom
+pub fn parse_latex<'a>(
+    dest_path: &Path,
+    name: &str,
+    content: &Content<'a>,
+    origin: &Location,
+    zoom: f32,
+) -> Result<Replacement<'a>> {
+    let tex = content.math();
+    let path = dest_path.join(name);
 
     // create a new tex file containing the equation
     if !path.with_extension("tex").exists() {
@@ -200,27 +596,35 @@ pub fn parse_latex<'a>(dest_path: &Path, content: &Content<'a>) -> Result<Replac
         file.write_all(tex.as_bytes())?;
     }
 
-    generate_svg_from_latex(&path, 1.0)?;
+    generate_svg_from_latex(&path, zoom)
+        .map_err(|err| locate(err, origin, 1, content.as_ref()))?;
 
     Ok(Replacement {
         content: content.clone(),
         intermediate: None,
-        svg: PathBuf::from(name + ".svg"),
+        svg: PathBuf::from(name).with_extension("svg"),
+        metrics: None,
     })
 }
 
-/// Parse a gnuplot file and generate a SVG file
-pub fn parse_gnuplot<'a>(dest_path: &Path, content: &Content<'a>) -> Result<Replacement<'a>> {
-    let name = hash(content);
-    let path = dest_path.join(&name);
+/// Parse a gnuplot file and generate a SVG file with the given zoom
+pub fn parse_gnuplot<'a>(
+    dest_path: &Path,
+    name: &str,
+    content: &Content<'a>,
+    origin: &Location,
+    zoom: f32,
+) -> Result<Replacement<'a>> {
+    let path = dest_path.join(name);
 
     if !path.with_extension("tex").exists() {
         //let name_plot = format!("{}_plot", name);
-        generate_latex_from_gnuplot(dest_path, content, &name)?;
+        generate_latex_from_gnuplot(dest_path, content, name)?;
     }
 
     if !path.with_extension("svg").exists() {
-        generate_svg_from_latex(&path, 1.0)?;
+        // the tex file is generated, so there is nothing closer than the block
+        generate_svg_from_latex(&path, zoom).map_err(|err| locate(err, origin, 0, ""))?;
     }
 
     let intermediate = fs::read_to_string(path.with_extension("tex"))?;
@@ -228,58 +632,69 @@ pub fn parse_gnuplot<'a>(dest_path: &Path, content: &Content<'a>) -> Result<Repl
     Ok(Replacement {
         content: content.to_owned(),
         intermediate: Some(intermediate),
-        svg: PathBuf::from(name + ".svg"),
+        svg: PathBuf::from(name).with_extension("svg"),
+        metrics: None,
     })
 }
 
 /// Parse gnuplot without using the latex backend
-pub fn parse_gnuplot_only<'a>(dest_path: &Path, content: &Content<'a>) -> Result<Replacement<'a>> {
-    let gnuplot_input = content.as_ref();
-    let name = hash(gnuplot_input);
-    let path = dest_path.join(&name);
+pub fn parse_gnuplot_only<'a>(
+    dest_path: &Path,
+    name: &str,
+    content: &Content<'a>,
+) -> Result<Replacement<'a>> {
+    let gnuplot_input = content.math();
+    let path = dest_path.join(name);
 
     if !path.with_extension("svg").exists() {
         let gnuplot_path = find_binary("gnuplot")?;
-        let cmd = Command::new(gnuplot_path)
-            .stdin(Stdio::piped())
-            .current_dir(dest_path)
-            .arg("-p")
-            .spawn()
-            .unwrap();
-        //.expect("Could not spawn gnuplot");
-
-        let mut stdin = cmd.stdin.unwrap();
-        stdin.write_all(format!("set output '{}.svg'\n", name).as_bytes())?;
-        stdin.write_all("set terminal svg\n".as_bytes())?;
-        stdin.write_all("set encoding utf8\n".as_bytes())?;
-        stdin.write_all(gnuplot_input.as_bytes())?;
+        let mut cmd = Command::new(gnuplot_path);
+        cmd.current_dir(dest_path).arg("-p");
+        let input = format!(
+            "set output '{}.svg'\nset terminal svg\nset encoding utf8\n{}",
+            name, gnuplot_input
+        );
+        let output = run(&mut cmd, Some(input.as_bytes()))?;
+        if !output.status.success() {
+            return Err(failed(&cmd, &output));
+        }
     }
 
     Ok(Replacement {
         content: content.clone(),
         intermediate: None,
-        svg: PathBuf::from(name + ".svg"),
+        svg: PathBuf::from(name).with_extension("svg"),
+        metrics: None,
     })
 }
 
 /// Generate html from BibTeX file using `bib2xhtml`
-pub fn bib_to_html(source: &str, bib2xhtml: &str) -> Result<String> {
-    let source = fs::canonicalize(source).unwrap();
-    let bib2xhtml = Path::new(bib2xhtml);
+///
+/// `bib2xhtml` lists every entry of `source`, cited or not. The `numeric` style and templates
+/// have no equivalent, their entries are numbered in the order of the file (`unsort`), so the
+/// labels only match the in-text citations if the file is in order of first citation.
+pub fn bib_to_html(source: &Path, bib2xhtml: &Path, style: &CitationStyle) -> Result<String> {
+    let source = fs::canonicalize(source)?;
+    let style = match style {
+        CitationStyle::Alpha => "alpha",
+        CitationStyle::AuthorYear => "named",
+        CitationStyle::Numeric | CitationStyle::Template(_) => "unsort",
+    };
 
     //./bib2xhtml.pl -s alpha -u -U ~/Documents/Bachelor_thesis/literature.bib
-    let cmd = Command::new(bib2xhtml.join("./bib2xhtml.pl"))
-        .current_dir(bib2xhtml)
-        .args(["-s", "alpha", "-u", "-U"])
-        .arg(source)
-        .output()
-        .expect("Could not spawn bib2xhtml");
+    let mut cmd = Command::new(bib2xhtml.join("./bib2xhtml.pl"));
+    cmd.current_dir(bib2xhtml)
+        .args(["-s", style, "-u", "-U"])
+        .arg(source);
+    let output = run(&mut cmd, None)?;
 
-    let buf = String::from_utf8_lossy(&cmd.stdout);
+    let buf = String::from_utf8_lossy(&output.stdout);
 
-    let err_str = String::from_utf8_lossy(&cmd.stderr);
+    let err_str = String::from_utf8_lossy(&output.stderr);
     if err_str.contains("error messages)") {
         Err(Error::InvalidBibliography(err_str.to_string()))
+    } else if !output.status.success() {
+        Err(failed(&cmd, &output))
     } else {
         let buf = buf
             .split('\n')
@@ -291,3 +706,172 @@ pub fn bib_to_html(source: &str, bib2xhtml: &str) -> Result<String> {
         Ok(buf)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+
+    #[test]
+    fn metrics_of_all_pages() {
+        let output = "pre-processing DVI file (format version 2)
+processing page 1
+  applying bounding box set by preview package (version 13.1)
+  width=33.1pt, height=6.94pt, depth=2.49pt
+  graphic size: 33.1pt x 9.43pt (11.64mm x 3.32mm)
+processing page 2
+  applying bounding box set by preview package (version 13.1)
+  width=8pt, height=4.3pt, depth=0pt
+2 of 2 pages converted in 0.1 seconds
+";
+        assert_eq!(
+            parse_metrics(output, 2.0),
+            vec![
+                Metrics {
+                    height: 13.88,
+                    depth: 4.98,
+                },
+                Metrics {
+                    height: 8.6,
+                    depth: 0.0,
+                },
+            ]
+        );
+        assert_eq!(parse_metrics("processing page 1\n", 1.0), vec![]);
+    }
+
+    #[test]
+    fn inline_documents() {
+        let template = Template::default();
+        let display = template.document("x^2", false);
+        let inline = template.document("x^2", true);
+        assert!(display.contains("\n$$x^2$$\n"));
+        assert!(inline.contains("\n$x^2$\n"));
+        assert!(!inline.contains("$$"));
+    }
+
+    #[test]
+    fn command_errors() {
+        let mut cmd = Command::new("mdbook-scientific-does-not-exist");
+        cmd.arg("a b");
+        assert_matches!(run(&mut cmd, None), Err(Error::CommandIo { command, .. }) => {
+            assert_eq!(command, "mdbook-scientific-does-not-exist 'a b'");
+        });
+
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "cat; echo oops >&2; exit 3"]);
+        let output = run(&mut cmd, Some(b"input")).unwrap();
+        assert_eq!(output.stdout, b"input");
+        assert_eq!(
+            failed(&cmd, &output).to_string(),
+            "`sh -c 'cat; echo oops >&2; exit 3'` failed with exit status: 3:\noops"
+        );
+    }
+
+    #[test]
+    fn preamble_with_dollars() {
+        let template = Template::new("% $$ is no slot\n\\newcommand{\\D}{$$}\n");
+        let document = template.document("x", false);
+        assert!(document.contains("\\newcommand{\\D}{$$}\n\\begin{document}"), "{}", document);
+        assert!(document.ends_with("$$x$$\n\\end{document}\n"), "{}", document);
+        let document = template.with("% $").document("y", true);
+        assert!(document.contains("% $\\begin{document}"), "{}", document);
+        assert!(document.contains("$y$"), "{}", document);
+
+        let (document, _) = batch_document(&template, &["x", "y"], false);
+        assert!(document.contains("\\begin{standalone}$$\nx\n$$"), "{}", document);
+    }
+
+    #[test]
+    fn latex_errors_located() {
+        let origin = Location {
+            path: Some(PathBuf::from("chapter.md")),
+            lico: LiCo {
+                lineno: 10,
+                column: 3,
+            },
+            line: "$$\\frac{a}".to_owned(),
+        };
+        let content = "\\frac{a}\n  \\badcmd x\n";
+        let error = |lineno: usize, context: &str| Error::InvalidMath {
+            message: "Undefined control sequence.".to_owned(),
+            context: context.to_owned(),
+            location: Location::at(LiCo { lineno, column: 1 }),
+        };
+        let located = |lineno, context| match locate(error(lineno, context), &origin, 20, content) {
+            Error::InvalidMath { location, .. } => location,
+            err => panic!("{}", err),
+        };
+
+        let location = located(21, "\\badcmd");
+        assert_eq!(location.path, origin.path);
+        assert_eq!(
+            location.lico,
+            LiCo {
+                lineno: 11,
+                column: 9
+            }
+        );
+        assert_eq!(location.line, "  \\badcmd x");
+        assert_eq!(
+            located(20, "\\frac{a}").lico,
+            LiCo {
+                lineno: 10,
+                column: 10
+            }
+        );
+        assert_eq!(located(3, "\\usepackage{nope}"), origin);
+        assert_eq!(located(40, ""), origin);
+    }
+
+    #[test]
+    fn batch_errors_located() {
+        let contents = ["a", "b\n\\badcmd x", "c"];
+        let (document, first_lines) = batch_document(&Template::default(), &contents, false);
+        let lines = Vec::from_iter(document.lines());
+        assert_eq!(first_lines.len(), 3);
+        for (content, first) in contents.iter().zip(&first_lines) {
+            assert_eq!(lines[first - 1], content.lines().next().unwrap());
+            assert_eq!(lines[first - 2], "\\begin{standalone}$$");
+        }
+
+        let lico = |lineno, column| LiCo { lineno, column };
+        let origins = Vec::from_iter((1..=3).map(|k| Location::at(lico(10 * k, 1))));
+        let located = |lineno, context: &str| {
+            let err = Error::InvalidMath {
+                message: "Undefined control sequence.".to_owned(),
+                context: context.to_owned(),
+                location: Location::at(lico(lineno, 1)),
+            };
+            match locate_in_batch(err, &first_lines, &origins, &contents) {
+                Error::InvalidMath { location, .. } => location.lico,
+                err => panic!("{}", err),
+            }
+        };
+        assert_eq!(located(first_lines[1] + 1, "\\badcmd"), lico(21, 7));
+        assert_eq!(located(first_lines[0], "a"), lico(10, 1));
+        // the closing delimiter is not part of the content
+        assert_eq!(located(first_lines[2] + 1, ""), lico(30, 1));
+    }
+
+    #[test]
+    fn batch_pages_in_order() {
+        let dir = std::env::temp_dir().join("mdbook-scientific-batch-pages");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for name in [
+            "batch-a-2.svg",
+            "batch-a-10.svg",
+            "batch-a-01.svg",
+            "batch-ab-3.svg",
+            "batch-a-4.tex",
+            "batch-a.svg",
+        ] {
+            fs::write(dir.join(name), "<svg/>").unwrap();
+        }
+        assert_eq!(
+            batch_pages(&dir, "batch-a").unwrap(),
+            ["batch-a-01.svg", "batch-a-2.svg", "batch-a-10.svg"]
+        );
+    }
+}
@@ -1,19 +1,26 @@
+mod bibliography;
+mod cache;
+pub use cache::{collect_garbage, remove_garbage, Garbage};
 mod fragments;
+mod mathml;
 mod preprocess;
 
-use crate::errors::Error;
 use fs_err as fs;
+use itertools::Itertools;
 use preprocess::replace_mermaid_charts;
 use std::collections::HashMap;
-use std::path::Path;
 use std::path::PathBuf;
 use std::str::FromStr;
 
 use mdbook::book::{Book, BookItem, Chapter};
 use mdbook::preprocess::{Preprocessor, PreprocessorContext};
-use nom_bibtex::*;
 
-use preprocess::replace_blocks;
+use bibliography::Citations;
+use cache::Cache;
+use preprocess::{
+    collect_fragments, collect_references, render_fragments, replace_blocks, BookContext,
+    ChapterInfo, Counters, References,
+};
 
 pub mod errors;
 pub use self::errors::*;
@@ -24,6 +31,7 @@ pub use self::types::*;
 #[cfg(test)]
 mod tests;
 
+#[derive(Default)]
 pub struct Scientific;
 
 impl Scientific {
@@ -54,136 +62,184 @@ impl Preprocessor for Scientific {
 impl Scientific {
     fn run_inner(&self, ctx: &PreprocessorContext, mut book: Book) -> Result<Book> {
         if let Some(cfg) = ctx.config.get_preprocessor(self.name()) {
+            let cfg = ScientificConfig::from_table(cfg)?;
             let renderer = SupportedRenderer::from_str(ctx.renderer.as_str())?;
 
-            let fragment_path = cfg
-                .get("fragment_path")
-                .map(|x| x.as_str().expect("Fragment path is valid UTF8. qed"))
-                .unwrap_or("fragments/");
+            fs::create_dir_all(&cfg.fragment_path)?;
 
-            let fragment_path = Path::new(fragment_path);
+            let fragment_path = fs::canonicalize(&cfg.fragment_path)?;
 
-            fs::create_dir_all(fragment_path)?;
-
-            let fragment_path = fs::canonicalize(fragment_path)?;
-
-            // track which fragments we use to copy them into the assets folder
-            let mut used_fragments = Vec::new();
-            // track which references are created
-            let mut references = HashMap::new();
-            // if there occurs an error skip everything and return the error
-            let mut error = Ok::<_, Error>(());
-
-            match renderer {
-                SupportedRenderer::Markdown | SupportedRenderer::Html => {
-                    // load all references in the bibliography and export to html
-                    if let (Some(bib), Some(bib2xhtml)) =
-                        (cfg.get("bibliography"), cfg.get("bib2xhtml"))
-                    {
-                        let bib = bib.as_str().unwrap();
-                        let bib2xhtml = bib2xhtml.as_str().expect("bib string is valid UTF8. qed");
-
-                        if !Path::new(bib).exists() {
-                            return Err(Error::BibliographyMissing(bib.to_owned()));
-                        }
-
-                        // read entries in bibtex file
-                        let bibtex = fs::read_to_string(bib)?;
-                        let bibtex = Bibtex::parse(&bibtex)?;
-                        for (i, entry) in bibtex.bibliographies().iter().enumerate() {
-                            references
-                                .insert(entry.citation_key().to_string(), format!("[{}]", i + 1));
-                        }
-
-                        // create bibliography
-                        let content = fragments::bib_to_html(bib, bib2xhtml)?;
-
-                        // add final chapter for bibliography
-                        let bib_chapter = Chapter::new(
-                            "Bibliography",
-                            format!("# Bibliography\n{}", content),
-                            PathBuf::from("bibliography.md"),
-                            Vec::new(),
-                        );
-                        book.push_item(bib_chapter);
-                    }
-                }
-                SupportedRenderer::Tectonic | SupportedRenderer::Latex => {
-                    //native support for bibtex, no need to fuck around
+            // load all references in the bibliography, to be rendered as html
+            let citations = match (renderer, &cfg.bibliography) {
+                (SupportedRenderer::Markdown | SupportedRenderer::Html, Some(bib)) => {
+                    Citations::new(
+                        cfg.citation_style.clone(),
+                        bibliography::load(bib)?,
+                        &cfg.nocite,
+                    )
                 }
+                // native support for bibtex in tectonic and latex, no need to fuck around
+                _ => Citations::default(),
+            };
+
+            let mut bctx = BookContext {
+                config: &cfg,
+                cache: Cache::open(&fragment_path),
+                template: fragments::Template::new(&cfg.latex_preamble(&ctx.root)?),
+                fragment_path,
+                renderer,
+                references: References::default(),
+                counters: Counters::default(),
+                citations,
+                used_fragments: Vec::new(),
+                embedded: 0,
+                client_math: 0,
+                errors: Vec::new(),
+                failed: HashMap::new(),
+            };
+
+            // within the `src` directory, which gets copied to the output directory as is
+            let dest = cfg.assets_output_path(&ctx.root.join(&ctx.config.book.src));
+            if !dest.exists() {
+                fs::create_dir_all(&dest)?;
             }
 
-            // assets path
-            let asset_path = cfg
-                .get("assets")
-                .map(|x| x.as_str().expect("Assumes valid UTF8 for assets. qed"))
-                .unwrap_or("src/");
-            let asset_path = ctx.root.join(asset_path);
-
-            // replace mermaid charts with prerendered svgs
+            // replace mermaid charts with prerendered svgs, which are written to the assets
+            // output directly and hence neither copied nor collected like fragments
+            let mut charts = Vec::new();
             book.for_each_mut(|item| {
                 if let BookItem::Chapter(ref mut ch) = item {
-                    ch.content = replace_mermaid_charts(
+                    let chapterno = ch
+                        .number
+                        .as_ref()
+                        .map(ToString::to_string)
+                        .unwrap_or_default();
+                    match replace_mermaid_charts(
                         ch.content.as_str(),
-                        ch.number.as_ref().unwrap().to_string(),
-                        &asset_path,
+                        chapterno,
+                        ch.path.as_deref(),
+                        &dest,
+                        &cfg.assets_output,
                         renderer,
-                        &mut used_fragments,
-                    )
-                    .unwrap();
+                        &mut charts,
+                    ) {
+                        Ok(content) => ch.content = content,
+                        // the charts stay code blocks
+                        Err(err) => bctx.errors.push(err),
+                    }
                 }
             });
 
-            // process blocks like `$$ .. $$`
-            book.for_each_mut(|item| {
-                if let Err(_) = error {
-                    return;
+            // collect all labels first, so references can point forward and across chapters
+            let mut fragments = Vec::new();
+            for item in book.iter() {
+                if let BookItem::Chapter(ref ch) = item {
+                    let chapter = ChapterInfo::new(ch);
+                    collect_references(&mut bctx, &ch.content, &chapter);
+                    collect_fragments(&cfg, &ch.content, &chapter, &mut fragments);
                 }
+            }
 
-                if let BookItem::Chapter(ref mut ch) = item {
-                    let head_number = ch
-                        .number
-                        .as_ref()
-                        .map(|x| x.to_string())
-                        .unwrap_or_default();
+            // render all missing fragments of the book concurrently, chapters only pick them up
+            // and report the errors of failed ones at their source
+            render_fragments(&mut bctx, fragments);
+            bctx.cache.save()?;
 
-                    match replace_blocks(
-                        &fragment_path,
-                        &asset_path,
-                        &ch.content,
-                        &head_number,
-                        renderer,
-                        &mut used_fragments,
-                        &mut references,
-                    ) {
-                        Ok(mut reconstructed) => {
-                            reconstructed.push('\n');
+            // process blocks like `$$ .. $$`
+            book.for_each_mut(|item| {
+                if let BookItem::Chapter(ref mut ch) = item {
+                    match replace_blocks(&mut bctx, &ch.content, &ChapterInfo::new(ch)) {
+                        Ok(reconstructed) => {
                             if reconstructed != ch.content {
-                                // for line in ch.content.lines() {
-                                //     eprintln!("- {}", line);
-                                // }
-                                // for line in reconstructed.lines() {
-                                //     eprintln!("+ {}", line);
-                                // }
                                 ch.content = reconstructed;
                             }
                         }
-                        Err(err) => error = Err(err),
+                        Err(err) => bctx.errors.push(err),
                     }
                 }
             });
 
-            error?;
+            // latex renderers typeset the equations themselves, so they need the macros too
+            let macros = cfg.macro_definitions();
+            let latex = matches!(
+                renderer,
+                SupportedRenderer::Latex | SupportedRenderer::Tectonic
+            );
+            // packages can only be loaded by the preamble of the renderer's own template
+            if latex && (cfg.preamble.is_some() || !cfg.preambles.is_empty()) {
+                eprintln!(
+                    "Warning: `preamble` and `preambles` are not passed on to the `{}` renderer, \
+                     load their packages in its preamble",
+                    ctx.renderer
+                );
+            }
+            if latex && !macros.is_empty() {
+                let mut exported = false;
+                book.for_each_mut(|item| {
+                    if let BookItem::Chapter(ref mut ch) = item {
+                        if !exported {
+                            ch.content.insert_str(0, &format!("{}\n", macros));
+                            exported = true;
+                        }
+                    }
+                });
+            }
 
-            // the output path is `src/storage/assets`, which get copied to the output directory
-            let dest = ctx.root.join("src").join("storage").join("assets");
-            if !dest.exists() {
-                fs::create_dir_all(&dest)?;
+            if let (SupportedRenderer::Markdown | SupportedRenderer::Html, Some(bib)) =
+                (renderer, &cfg.bibliography)
+            {
+                let uncited = Vec::from_iter(bctx.citations.uncited());
+                if !uncited.is_empty() {
+                    const SHOWN: usize = 10;
+                    // `bib2xhtml` lists all entries of the file regardless
+                    let fate = if cfg.bib2xhtml.is_some() {
+                        "yet listed by `bib2xhtml`"
+                    } else {
+                        "and hence omitted"
+                    };
+                    eprintln!(
+                        "Warning: {} bibliography entries are never cited {}: {}{}",
+                        uncited.len(),
+                        fate,
+                        uncited.iter().take(SHOWN).join(", "),
+                        if uncited.len() > SHOWN { ", .." } else { "" }
+                    );
+                }
+
+                // create bibliography, natively unless `bib2xhtml` is explicitly configured
+                let content = if let Some(ref bib2xhtml) = cfg.bib2xhtml {
+                    fragments::bib_to_html(bib, bib2xhtml, &cfg.citation_style)?
+                } else {
+                    bctx.citations.render_html()
+                };
+
+                // add final chapter for bibliography
+                let bib_chapter = Chapter::new(
+                    "Bibliography",
+                    format!("# Bibliography\n{}", content),
+                    PathBuf::from(bibliography::BIBLIOGRAPHY_PATH),
+                    Vec::new(),
+                );
+                book.push_item(bib_chapter);
             }
 
             // copy all used fragments
-            for fragment in used_fragments {
-                fs::copy(fragment_path.join(&fragment), dest.join(&fragment))?;
+            for fragment in &bctx.used_fragments {
+                fs::copy(bctx.fragment_path.join(fragment), dest.join(fragment))?;
+            }
+
+            // remember what this build used, everything else is garbage
+            bctx.cache.set_used(&ctx.renderer, &bctx.used_fragments);
+            bctx.cache.save()?;
+
+            // missing binaries fail every fragment alike
+            let errors = Vec::from_iter(bctx.errors.into_iter().unique_by(ToString::to_string));
+            if cfg.strict {
+                Error::all(errors)?;
+            } else {
+                for err in errors {
+                    eprintln!("Warning: {}", err);
+                }
             }
 
             Ok(book)
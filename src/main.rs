@@ -1,8 +1,9 @@
 use clap::Parser;
 use mdbook::preprocess::{CmdPreprocessor, Preprocessor};
 use mdbook_scientific::errors::*;
-use mdbook_scientific::Scientific;
+use mdbook_scientific::{collect_garbage, remove_garbage, Scientific, ScientificConfig};
 use std::io;
+use std::path::{Path, PathBuf};
 use std::process;
 
 #[derive(clap::Parser, Debug)]
@@ -20,21 +21,32 @@ struct Args {
 #[derive(clap::Subcommand, Debug)]
 #[doc = "Check whether a renderer is supported by this preprocessor"]
 enum Sub {
-    Supports { renderer: String },
+    Supports {
+        renderer: String,
+    },
+    /// Remove fragments and intermediate files which the latest build did not use
+    #[command(alias = "clean")]
+    Gc {
+        /// Root directory of the book
+        #[arg(default_value = ".")]
+        root: PathBuf,
+        /// Only list the files which would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
 }
 
 fn main() -> color_eyre::eyre::Result<()> {
-    eprintln!("INVOKE MDBOOK SCIENTIFIC!");
     color_eyre::install()?;
 
     let args = Args::try_parse()?;
 
     let preprocessor = Scientific::new();
 
-    if let Some(Sub::Supports { ref renderer }) = args.supports {
-        handle_supports(&preprocessor, renderer);
-    } else {
-        handle_preprocessing(&preprocessor)?;
+    match args.supports {
+        Some(Sub::Supports { ref renderer }) => handle_supports(&preprocessor, renderer),
+        Some(Sub::Gc { ref root, dry_run }) => handle_gc(root, dry_run)?,
+        None => handle_preprocessing(&preprocessor)?,
     }
     Ok(())
 }
@@ -56,13 +68,55 @@ fn handle_preprocessing(pre: &dyn Preprocessor) -> Result<()> {
         );
     }
 
-    let processed_book = dbg!(pre.run(&ctx, dbg!(book))?);
+    let processed_book = pre.run(&ctx, book)?;
 
     serde_json::to_writer(io::stdout(), &processed_book)?;
 
     Ok(())
 }
 
+fn handle_gc(root: &Path, dry_run: bool) -> Result<()> {
+    let config = mdbook::Config::from_disk(root.join("book.toml"))?;
+    let table = config
+        .get_preprocessor("scientific")
+        .ok_or(Error::KeySectionNotFound)?;
+    let cfg = ScientificConfig::from_table(table)?;
+    let fragment_path = root.join(&cfg.fragment_path);
+
+    let assets_output_path = cfg.assets_output_path(&root.join(&config.book.src));
+
+    let garbage = collect_garbage(&fragment_path, &assets_output_path)?;
+    for file in &garbage {
+        println!("{:>10}  {}", human_size(file.size), file.path.display());
+    }
+    let total = human_size(garbage.iter().map(|file| file.size).sum());
+    if dry_run {
+        println!("Would remove {} files, {} in total", garbage.len(), total);
+    } else {
+        remove_garbage(&fragment_path, &assets_output_path, &garbage)?;
+        println!("Removed {} files, {} in total", garbage.len(), total);
+    }
+    Ok(())
+}
+
+/// Size in bytes with a binary unit, i.e. `1.5 KiB`
+fn human_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.;
+    let mut unit = UNITS[0];
+    for next in &UNITS[1..] {
+        if size < 1024. {
+            break;
+        }
+        size /= 1024.;
+        unit = next;
+    }
+    format!("{:.1} {}", size, unit)
+}
+
 fn handle_supports(pre: &dyn Preprocessor, renderer: impl AsRef<str>) -> ! {
     let supported = pre.supports_renderer(renderer.as_ref());
 
@@ -103,4 +157,28 @@ mod tests {
         } => {
         });
     }
+
+    #[test]
+    fn clap_gc() {
+        assert_matches!(Args::try_parse_from(vec!["mdbook-scientific", "clean", "--dry-run"]).unwrap(),
+        Args {
+            supports: Some(Sub::Gc { root, dry_run: true }),
+        } => {
+            assert_eq!(root, PathBuf::from("."));
+        });
+        assert_matches!(
+            Args::try_parse_from(vec!["mdbook-scientific", "gc", "book"]).unwrap(),
+            Args {
+                supports: Some(Sub::Gc { dry_run: false, .. }),
+            }
+        );
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(human_size(12), "12 B");
+        assert_eq!(human_size(1536), "1.5 KiB");
+        assert_eq!(human_size(3 * 1024 * 1024), "3.0 MiB");
+        assert_eq!(human_size(5 * 1024 * 1024 * 1024 * 1024), "5120.0 GiB");
+    }
 }
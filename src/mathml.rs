@@ -0,0 +1,1128 @@
+//! Conversion of TeX math to MathML, so books build without a TeX installation.
+//!
+//! Only a small subset of LaTeX math is supported:
+//!
+//! * letters, numbers, operators and the commands of the symbol tables below,
+//! * sub- and superscripts and primes, placed as limits for `\sum` and friends,
+//! * `\frac`, `\binom` and `\sqrt` with an optional index,
+//! * `\left` and `\right` with the common delimiters,
+//! * plain text by `\text`, `\mbox` and `\operatorname`,
+//! * the fonts `\mathrm`, `\mathbf`, `\mathbb` and `\mathcal`,
+//! * the accents `\hat`, `\tilde`, `\bar`, `\vec`, `\dot`, `\ddot`, `\overline` and
+//!   `\underline`,
+//! * the spaces `~`, `\,`, `\:`, `\;`, `\!`, `\ `, `\quad` and `\qquad`,
+//! * the environments `matrix`, `pmatrix`, `bmatrix`, `cases` and `aligned`,
+//! * the configured macros.
+//!
+//! Anything else is reported as [`Unsupported`], such equations are rendered to svg instead.
+
+use std::collections::BTreeMap;
+
+use crate::bibliography::escape_html;
+use crate::types::macro_arity;
+
+/// Nesting of macro expansions, deeper ones are most likely recursive
+const MAX_DEPTH: usize = 32;
+
+/// A construct the converter does not know
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unsupported(pub String);
+
+impl std::fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "`{}` is not supported", self.0)
+    }
+}
+
+type Convert<T> = std::result::Result<T, Unsupported>;
+
+/// Convert `tex` to a `<math>` element, as a block if `display`.
+///
+/// `macros` are expanded like the ones defined by the configuration.
+pub fn convert(tex: &str, display: bool, macros: &BTreeMap<String, String>) -> Convert<String> {
+    let mut parser = Parser::new(tex, macros, 0, None);
+    let (row, stop) = parser.row()?;
+    if stop != Stop::Eof {
+        return Err(stop.unexpected());
+    }
+    Ok(format!(
+        r#"<math xmlns="http://www.w3.org/1998/Math/MathML"{}>{}</math>"#,
+        if display { r#" display="block""# } else { "" },
+        row.concat()
+    ))
+}
+
+/// Why a row of math ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Stop {
+    Eof,
+    Close,
+    Right,
+    Ampersand,
+    Newline,
+    End(String),
+}
+
+impl Stop {
+    fn unexpected(self) -> Unsupported {
+        Unsupported(match self {
+            Stop::Eof => "missing `}`".to_owned(),
+            Stop::Close => "}".to_owned(),
+            Stop::Right => "\\right".to_owned(),
+            Stop::Ampersand => "&".to_owned(),
+            Stop::Newline => "\\\\".to_owned(),
+            Stop::End(env) => format!("\\end{{{}}}", env),
+        })
+    }
+}
+
+/// Font of letters and digits, set by `\mathbb` and friends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Variant {
+    Normal,
+    Bold,
+    DoubleStruck,
+    Script,
+}
+
+impl Variant {
+    /// The mathematical alphanumeric symbol of `c`, if there is one
+    fn style(self, c: char) -> char {
+        // code points of `A`, `a` and `0`, and the letters which are located elsewhere
+        let (upper, lower, digit, exceptions): (u32, u32, Option<u32>, &[(char, char)]) = match self
+        {
+            Variant::Normal => return c,
+            Variant::Bold => (0x1D400, 0x1D41A, Some(0x1D7CE), &[]),
+            Variant::DoubleStruck => (
+                0x1D538,
+                0x1D552,
+                Some(0x1D7D8),
+                &[
+                    ('C', 'ℂ'),
+                    ('H', 'ℍ'),
+                    ('N', 'ℕ'),
+                    ('P', 'ℙ'),
+                    ('Q', 'ℚ'),
+                    ('R', 'ℝ'),
+                    ('Z', 'ℤ'),
+                ],
+            ),
+            Variant::Script => (
+                0x1D49C,
+                0x1D4B6,
+                None,
+                &[
+                    ('B', 'ℬ'),
+                    ('E', 'ℰ'),
+                    ('F', 'ℱ'),
+                    ('H', 'ℋ'),
+                    ('I', 'ℐ'),
+                    ('L', 'ℒ'),
+                    ('M', 'ℳ'),
+                    ('R', 'ℛ'),
+                    ('e', 'ℯ'),
+                    ('g', 'ℊ'),
+                    ('o', 'ℴ'),
+                ],
+            ),
+        };
+        if let Some((_, styled)) = exceptions.iter().find(|(plain, _)| *plain == c) {
+            return *styled;
+        }
+        let code = match c {
+            'A'..='Z' => Some(upper + (c as u32 - 'A' as u32)),
+            'a'..='z' => Some(lower + (c as u32 - 'a' as u32)),
+            '0'..='9' => digit.map(|digit| digit + (c as u32 - '0' as u32)),
+            _ => None,
+        };
+        code.and_then(char::from_u32).unwrap_or(c)
+    }
+}
+
+/// A single element, possibly followed by scripts
+#[derive(Debug, Default)]
+struct Atom {
+    mathml: String,
+    /// Scripts are placed below and above, like for `\sum`
+    limits: bool,
+    /// Followed by an invisible function application, like `\sin`
+    function: bool,
+}
+
+impl Atom {
+    fn new(mathml: String) -> Self {
+        Self {
+            mathml,
+            ..Default::default()
+        }
+    }
+}
+
+enum Item {
+    Atom(Atom),
+    Stop(Stop),
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    pos: usize,
+    macros: &'a BTreeMap<String, String>,
+    /// Nesting of macro expansions
+    depth: usize,
+    variant: Option<Variant>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(
+        src: &'a str,
+        macros: &'a BTreeMap<String, String>,
+        depth: usize,
+        variant: Option<Variant>,
+    ) -> Self {
+        Self {
+            src,
+            pos: 0,
+            macros,
+            depth,
+            variant,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    /// Skip whitespace and comments
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek() {
+            match c {
+                '%' => {
+                    let rest = &self.src[self.pos..];
+                    self.pos += rest.find('\n').unwrap_or(rest.len());
+                }
+                c if c.is_whitespace() => {
+                    self.bump();
+                }
+                _ => break,
+            }
+        }
+    }
+
+    /// Name of a command, the backslash is consumed already
+    fn command_name(&mut self) -> &'a str {
+        let start = self.pos;
+        let rest = &self.src[start..];
+        let len = rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(rest.len());
+        if len > 0 {
+            self.pos += len;
+            let name = &self.src[start..self.pos];
+            self.skip_whitespace();
+            name
+        } else {
+            self.bump();
+            &self.src[start..self.pos]
+        }
+    }
+
+    /// Source of a `{..}` group without the braces, or of a single token
+    fn raw_group(&mut self) -> Convert<&'a str> {
+        self.skip_whitespace();
+        let start = self.pos;
+        match self.bump() {
+            Some('{') => {
+                let mut depth = 0;
+                while let Some(c) = self.bump() {
+                    match c {
+                        '\\' => {
+                            self.bump();
+                        }
+                        '{' => depth += 1,
+                        '}' if depth == 0 => return Ok(&self.src[start + 1..self.pos - 1]),
+                        '}' => depth -= 1,
+                        _ => {}
+                    }
+                }
+                Err(Stop::Eof.unexpected())
+            }
+            Some('\\') => {
+                self.command_name();
+                Ok(self.src[start..self.pos].trim_end())
+            }
+            Some('}') | None => Err(Unsupported("missing argument".to_owned())),
+            Some(_) => Ok(&self.src[start..self.pos]),
+        }
+    }
+
+    /// Source of an optional `[..]` argument without the brackets
+    fn raw_optional(&mut self) -> Convert<Option<&'a str>> {
+        self.skip_whitespace();
+        let rest = &self.src[self.pos..];
+        let Some(rest) = rest.strip_prefix('[') else {
+            return Ok(None);
+        };
+        let mut depth = 0;
+        for (idx, c) in rest.char_indices() {
+            match c {
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                ']' if depth == 0 => {
+                    self.pos += idx + 2;
+                    return Ok(Some(&rest[..idx]));
+                }
+                _ => {}
+            }
+        }
+        Err(Unsupported("missing `]`".to_owned()))
+    }
+
+    /// Convert `src` on its own, as a single element
+    fn sub(&self, src: &str, depth: usize, variant: Option<Variant>) -> Convert<String> {
+        let mut parser = Parser::new(src, self.macros, depth, variant);
+        let (row, stop) = parser.row()?;
+        if stop != Stop::Eof {
+            return Err(stop.unexpected());
+        }
+        Ok(mrow(row))
+    }
+
+    /// A mandatory argument, either a group or a single token
+    fn argument(&mut self) -> Convert<String> {
+        self.skip_whitespace();
+        if self.peek() == Some('{') {
+            let group = self.raw_group()?;
+            return self.sub(group, self.depth, self.variant);
+        }
+        match self.atom(true)? {
+            Item::Atom(atom) => Ok(atom.mathml),
+            Item::Stop(_) => Err(Unsupported("missing argument".to_owned())),
+        }
+    }
+
+    /// Elements until the end of the source, a group or an environment cell
+    fn row(&mut self) -> Convert<(Vec<String>, Stop)> {
+        let mut row = Vec::new();
+        loop {
+            self.skip_whitespace();
+            match self.atom(false)? {
+                Item::Atom(atom) => row.push(self.scripts(atom)?),
+                Item::Stop(stop) => return Ok((row, stop)),
+            }
+        }
+    }
+
+    /// Attach sub- and superscripts to `atom`
+    fn scripts(&mut self, atom: Atom) -> Convert<String> {
+        let (mut sub, mut sup, mut primes) = (None, None, 0);
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                Some('_') if sub.is_none() => {
+                    self.bump();
+                    sub = Some(self.argument()?);
+                }
+                Some('^') if sup.is_none() => {
+                    self.bump();
+                    sup = Some(self.argument()?);
+                }
+                Some('_' | '^') => return Err(Unsupported("double script".to_owned())),
+                Some('\'') => {
+                    self.bump();
+                    primes += 1;
+                }
+                _ => break,
+            }
+        }
+        if primes > 0 {
+            let primes = format!("<mo>{}</mo>", "&#x2032;".repeat(primes));
+            sup = Some(match sup {
+                Some(sup) => format!("<mrow>{}{}</mrow>", primes, sup),
+                None => primes,
+            });
+        }
+
+        let base = atom.mathml;
+        let (under, over, both) = if atom.limits {
+            ("munder", "mover", "munderover")
+        } else {
+            ("msub", "msup", "msubsup")
+        };
+        let mut scripted = match (sub, sup) {
+            (None, None) => base,
+            (Some(sub), None) => format!("<{under}>{base}{sub}</{under}>"),
+            (None, Some(sup)) => format!("<{over}>{base}{sup}</{over}>"),
+            (Some(sub), Some(sup)) => format!("<{both}>{base}{sub}{sup}</{both}>"),
+        };
+        if atom.function {
+            scripted += "<mo>&#x2061;</mo>";
+        }
+        Ok(scripted)
+    }
+
+    /// The next element, without scripts
+    ///
+    /// A `single` token is a single digit, like the `2` of `x^23`.
+    fn atom(&mut self, single: bool) -> Convert<Item> {
+        let Some(c) = self.peek() else {
+            return Ok(Item::Stop(Stop::Eof));
+        };
+        let atom = match c {
+            '}' => {
+                self.bump();
+                return Ok(Item::Stop(Stop::Close));
+            }
+            '&' => {
+                self.bump();
+                return Ok(Item::Stop(Stop::Ampersand));
+            }
+            '{' => {
+                let group = self.raw_group()?;
+                Atom::new(self.sub(group, self.depth, self.variant)?)
+            }
+            '\\' => {
+                self.bump();
+                return self.command();
+            }
+            // scripts without a base
+            '^' | '_' | '\'' => Atom::new("<mrow></mrow>".to_owned()),
+            '0'..='9' | '.' => {
+                let rest = &self.src[self.pos..];
+                let len = if single {
+                    usize::from(c != '.')
+                } else {
+                    rest.char_indices()
+                        .find(|&(idx, c)| {
+                            !(c.is_ascii_digit()
+                                || c == '.'
+                                    && rest[idx + 1..].starts_with(|c: char| c.is_ascii_digit()))
+                        })
+                        .map(|(idx, _)| idx)
+                        .unwrap_or(rest.len())
+                };
+                if len == 0 {
+                    // a lone `.`
+                    self.bump();
+                    Atom::new("<mo>.</mo>".to_owned())
+                } else {
+                    self.pos += len;
+                    let number = String::from_iter(
+                        rest[..len]
+                            .chars()
+                            .map(|c| self.variant.map_or(c, |variant| variant.style(c))),
+                    );
+                    Atom::new(format!("<mn>{}</mn>", number))
+                }
+            }
+            c if c.is_alphabetic() => {
+                self.bump();
+                Atom::new(identifier(c, self.variant))
+            }
+            '~' => {
+                self.bump();
+                Atom::new(r#"<mspace width="0.333em"></mspace>"#.to_owned())
+            }
+            '$' | '#' => return Err(Unsupported(c.to_string())),
+            c => {
+                self.bump();
+                let operator = match c {
+                    '-' => '\u{2212}',
+                    '*' => '\u{2217}',
+                    c => c,
+                };
+                Atom::new(format!("<mo>{}</mo>", escape(&operator.to_string())))
+            }
+        };
+        Ok(Item::Atom(atom))
+    }
+
+    /// The element of a command, the backslash is consumed already
+    fn command(&mut self) -> Convert<Item> {
+        let name = self.command_name();
+        let atom = match name {
+            "\\" => return Ok(Item::Stop(Stop::Newline)),
+            "right" => return Ok(Item::Stop(Stop::Right)),
+            "end" => return Ok(Item::Stop(Stop::End(self.raw_group()?.trim().to_owned()))),
+            "begin" => {
+                let env = self.raw_group()?.trim();
+                self.environment(env)?
+            }
+            "left" => {
+                let open = self.delimiter()?;
+                let (inner, stop) = self.row()?;
+                if stop != Stop::Right {
+                    return Err(Unsupported("\\left without \\right".to_owned()));
+                }
+                let close = self.delimiter()?;
+                Atom::new(format!(
+                    "<mrow>{}{}{}</mrow>",
+                    fence(&open),
+                    inner.concat(),
+                    fence(&close)
+                ))
+            }
+            "frac" => {
+                let (numerator, denominator) = (self.argument()?, self.argument()?);
+                Atom::new(format!("<mfrac>{}{}</mfrac>", numerator, denominator))
+            }
+            "binom" => {
+                let (n, k) = (self.argument()?, self.argument()?);
+                Atom::new(format!(
+                    r#"<mrow><mo>(</mo><mfrac linethickness="0">{}{}</mfrac><mo>)</mo></mrow>"#,
+                    n, k
+                ))
+            }
+            "sqrt" => match self.raw_optional()? {
+                Some(index) => {
+                    let index = self.sub(index, self.depth, self.variant)?;
+                    Atom::new(format!("<mroot>{}{}</mroot>", self.argument()?, index))
+                }
+                None => Atom::new(format!("<msqrt>{}</msqrt>", self.argument()?)),
+            },
+            "text" | "mbox" => Atom::new(format!("<mtext>{}</mtext>", text(self.raw_group()?)?)),
+            "operatorname" => Atom {
+                mathml: format!("<mi>{}</mi>", text(self.raw_group()?)?),
+                function: true,
+                ..Default::default()
+            },
+            "nonumber" | "notag" => Atom::default(),
+            "quad" => Atom::new(r#"<mspace width="1em"></mspace>"#.to_owned()),
+            "qquad" => Atom::new(r#"<mspace width="2em"></mspace>"#.to_owned()),
+            "," => Atom::new(r#"<mspace width="0.167em"></mspace>"#.to_owned()),
+            ":" => Atom::new(r#"<mspace width="0.222em"></mspace>"#.to_owned()),
+            ";" => Atom::new(r#"<mspace width="0.278em"></mspace>"#.to_owned()),
+            "!" => Atom::new(r#"<mspace width="-0.167em"></mspace>"#.to_owned()),
+            "" | " " => Atom::new(r#"<mspace width="0.333em"></mspace>"#.to_owned()),
+            "{" | "}" | "%" | "$" | "&" | "#" | "_" => {
+                Atom::new(format!("<mo>{}</mo>", escape(name)))
+            }
+            "|" => Atom::new("<mo>&#x2016;</mo>".to_owned()),
+            name => {
+                if let Some(variant) = font(name) {
+                    let group = self.raw_group()?;
+                    Atom::new(self.sub(group, self.depth, Some(variant))?)
+                } else if let Some(&(_, mark, over)) =
+                    ACCENTS.iter().find(|(accent, ..)| *accent == name)
+                {
+                    let base = self.argument()?;
+                    // lines stretch over the whole base, marks don't
+                    let stretchy = if name.ends_with("line") {
+                        r#" stretchy="true""#
+                    } else {
+                        ""
+                    };
+                    Atom::new(if over {
+                        format!(r#"<mover accent="true">{base}<mo{stretchy}>{mark}</mo></mover>"#)
+                    } else {
+                        format!(
+                            r#"<munder accentunder="true">{base}<mo{stretchy}>{mark}</mo></munder>"#
+                        )
+                    })
+                } else if let Some(body) = self.definition(name) {
+                    self.expand(body)?
+                } else if let Some(atom) = symbol(name, self.variant) {
+                    atom
+                } else {
+                    return Err(Unsupported(format!("\\{}", name)));
+                }
+            }
+        };
+        Ok(Item::Atom(atom))
+    }
+
+    /// Body of a configured macro
+    fn definition(&self, name: &str) -> Option<&'a str> {
+        self.macros
+            .iter()
+            .find(|(macro_name, _)| macro_name.trim_start_matches('\\') == name)
+            .map(|(_, body)| body.as_str())
+    }
+
+    /// Expand a configured macro, reading its arguments
+    fn expand(&mut self, body: &str) -> Convert<Atom> {
+        if self.depth >= MAX_DEPTH {
+            return Err(Unsupported("recursive macro".to_owned()));
+        }
+        let mut expanded = body.to_owned();
+        for arg in 1..=macro_arity(body).unwrap_or_default() {
+            let value = self.raw_group()?;
+            expanded = expanded.replace(&format!("#{}", arg), value);
+        }
+        Ok(Atom::new(self.sub(
+            &expanded,
+            self.depth + 1,
+            self.variant,
+        )?))
+    }
+
+    /// A delimiter after `\left` or `\right`, empty for `.`
+    fn delimiter(&mut self) -> Convert<String> {
+        self.skip_whitespace();
+        let delimiter = match self.bump() {
+            Some('.') => "",
+            Some('\\') => match self.command_name() {
+                "{" => "{",
+                "}" => "}",
+                "|" | "Vert" => "&#x2016;",
+                "vert" => "&#x7C;",
+                "langle" => "&#x27E8;",
+                "rangle" => "&#x27E9;",
+                name => return Err(Unsupported(format!("delimiter \\{}", name))),
+            },
+            Some(c @ ('(' | ')' | '[' | ']' | '|')) => return Ok(escape(&c.to_string())),
+            Some(c) => return Err(Unsupported(format!("delimiter {}", c))),
+            None => return Err(Unsupported("missing delimiter".to_owned())),
+        };
+        Ok(delimiter.to_owned())
+    }
+
+    /// The content of `\begin{env}` up to its `\end{env}`
+    fn environment(&mut self, env: &str) -> Convert<Atom> {
+        let (open, close, align): (&str, &str, &[&str]) = match env {
+            "matrix" => ("", "", &["center"]),
+            "pmatrix" => ("(", ")", &["center"]),
+            "bmatrix" => ("[", "]", &["center"]),
+            "cases" => ("{", "", &["left"]),
+            "aligned" => ("", "", &["right", "left"]),
+            env => return Err(Unsupported(format!("\\begin{{{}}}", env))),
+        };
+
+        let mut rows = Vec::new();
+        let mut cells = Vec::new();
+        loop {
+            let (cell, stop) = self.row()?;
+            let column = align[cells.len() % align.len()];
+            cells.push(format!(
+                r#"<mtd columnalign="{}">{}</mtd>"#,
+                column,
+                cell.concat()
+            ));
+            match stop {
+                Stop::Ampersand => continue,
+                Stop::Newline => rows.push(std::mem::take(&mut cells)),
+                Stop::End(end) if end == env => {
+                    // a trailing `\\` adds no row
+                    if cells.len() > 1 || !cell.is_empty() {
+                        rows.push(cells);
+                    }
+                    break;
+                }
+                stop => return Err(stop.unexpected()),
+            }
+        }
+
+        let display = if env == "aligned" {
+            r#" displaystyle="true""#
+        } else {
+            ""
+        };
+        let table = format!(
+            "<mtable{}>{}</mtable>",
+            display,
+            rows.iter()
+                .map(|cells| format!("<mtr>{}</mtr>", cells.concat()))
+                .collect::<String>()
+        );
+        Ok(Atom::new(if open.is_empty() && close.is_empty() {
+            table
+        } else {
+            format!("<mrow>{}{}{}</mrow>", fence(open), table, fence(close))
+        }))
+    }
+}
+
+/// Wrap multiple elements, so they form a single one
+fn mrow(row: Vec<String>) -> String {
+    let mut row = Vec::from_iter(row.into_iter().filter(|element| !element.is_empty()));
+    if row.len() == 1 {
+        row.remove(0)
+    } else {
+        format!("<mrow>{}</mrow>", row.concat())
+    }
+}
+
+/// A stretching delimiter, nothing for `\left.`
+fn fence(delimiter: &str) -> String {
+    if delimiter.is_empty() {
+        String::new()
+    } else {
+        format!(r#"<mo fence="true" stretchy="true">{}</mo>"#, delimiter)
+    }
+}
+
+/// Escape text, such that neither html, as by [`escape_html`], nor Markdown interpret any of it
+pub(crate) fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '*' | '_' | '`' | '\\' | '[' | ']' | '~' | '^' | '|' | '$' | '#' => {
+                escaped.push_str(&format!("&#x{:X};", c as u32))
+            }
+            c => escaped.push_str(&escape_html(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    escaped
+}
+
+/// Content of `\text`, only escaped special characters are allowed
+fn text(raw: &str) -> Convert<String> {
+    let mut unescaped = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(c @ ('%' | '&' | '_' | '#' | '$' | '{' | '}' | ' ')) => unescaped.push(c),
+                _ => return Err(Unsupported(format!("\\text{{{}}}", raw))),
+            },
+            '$' | '{' | '}' | '^' | '~' => return Err(Unsupported(format!("\\text{{{}}}", raw))),
+            c => unescaped.push(c),
+        }
+    }
+    Ok(escape(&unescaped))
+}
+
+/// An identifier, upright if it is set in a normal font
+fn identifier(c: char, variant: Option<Variant>) -> String {
+    match variant {
+        Some(Variant::Normal) => format!(
+            r#"<mi mathvariant="normal">{}</mi>"#,
+            escape(&c.to_string())
+        ),
+        Some(variant) => format!("<mi>{}</mi>", escape(&variant.style(c).to_string())),
+        None => format!("<mi>{}</mi>", escape(&c.to_string())),
+    }
+}
+
+/// Font of `\mathbb` and friends
+fn font(name: &str) -> Option<Variant> {
+    Some(match name {
+        "mathrm" => Variant::Normal,
+        "mathbf" => Variant::Bold,
+        "mathbb" => Variant::DoubleStruck,
+        "mathcal" => Variant::Script,
+        _ => return None,
+    })
+}
+
+/// Accents by name, with their mark and whether it is placed over the base
+const ACCENTS: &[(&str, &str, bool)] = &[
+    ("hat", "&#x5E;", true),
+    ("tilde", "&#x2DC;", true),
+    ("bar", "&#xAF;", true),
+    ("vec", "&#x2192;", true),
+    ("dot", "&#x2D9;", true),
+    ("ddot", "&#xA8;", true),
+    ("overline", "&#x203E;", true),
+    ("underline", "&#x5F;", false),
+];
+
+/// Identifiers, upright in TeX unless they are lower case Greek letters
+const IDENTIFIERS: &[(&str, char)] = &[
+    ("alpha", 'α'),
+    ("beta", 'β'),
+    ("gamma", 'γ'),
+    ("delta", 'δ'),
+    ("epsilon", 'ϵ'),
+    ("varepsilon", 'ε'),
+    ("zeta", 'ζ'),
+    ("eta", 'η'),
+    ("theta", 'θ'),
+    ("vartheta", 'ϑ'),
+    ("iota", 'ι'),
+    ("kappa", 'κ'),
+    ("lambda", 'λ'),
+    ("mu", 'μ'),
+    ("nu", 'ν'),
+    ("xi", 'ξ'),
+    ("pi", 'π'),
+    ("varpi", 'ϖ'),
+    ("rho", 'ρ'),
+    ("varrho", 'ϱ'),
+    ("sigma", 'σ'),
+    ("varsigma", 'ς'),
+    ("tau", 'τ'),
+    ("upsilon", 'υ'),
+    ("phi", 'ϕ'),
+    ("varphi", 'φ'),
+    ("chi", 'χ'),
+    ("psi", 'ψ'),
+    ("omega", 'ω'),
+    ("Gamma", 'Γ'),
+    ("Delta", 'Δ'),
+    ("Theta", 'Θ'),
+    ("Lambda", 'Λ'),
+    ("Xi", 'Ξ'),
+    ("Pi", 'Π'),
+    ("Sigma", 'Σ'),
+    ("Upsilon", 'Υ'),
+    ("Phi", 'Φ'),
+    ("Psi", 'Ψ'),
+    ("Omega", 'Ω'),
+    ("infty", '∞'),
+    ("partial", '∂'),
+    ("nabla", '∇'),
+    ("ell", 'ℓ'),
+    ("hbar", 'ℏ'),
+    ("hslash", 'ℏ'),
+    ("emptyset", '∅'),
+    ("varnothing", '∅'),
+    ("aleph", 'ℵ'),
+    ("Re", 'ℜ'),
+    ("Im", 'ℑ'),
+    ("wp", '℘'),
+    ("imath", 'ı'),
+    ("jmath", 'ȷ'),
+    ("angle", '∠'),
+    ("triangle", '△'),
+    ("top", '⊤'),
+    ("bot", '⊥'),
+];
+
+/// Operators, relations and punctuation
+const OPERATORS: &[(&str, char)] = &[
+    ("pm", '±'),
+    ("mp", '∓'),
+    ("times", '×'),
+    ("div", '÷'),
+    ("cdot", '⋅'),
+    ("ast", '∗'),
+    ("star", '⋆'),
+    ("circ", '∘'),
+    ("bullet", '∙'),
+    ("oplus", '⊕'),
+    ("ominus", '⊖'),
+    ("otimes", '⊗'),
+    ("odot", '⊙'),
+    ("wedge", '∧'),
+    ("land", '∧'),
+    ("vee", '∨'),
+    ("lor", '∨'),
+    ("neg", '¬'),
+    ("lnot", '¬'),
+    ("setminus", '∖'),
+    ("cap", '∩'),
+    ("cup", '∪'),
+    ("sqcup", '⊔'),
+    ("dagger", '†'),
+    ("leq", '≤'),
+    ("le", '≤'),
+    ("geq", '≥'),
+    ("ge", '≥'),
+    ("neq", '≠'),
+    ("ne", '≠'),
+    ("ll", '≪'),
+    ("gg", '≫'),
+    ("approx", '≈'),
+    ("sim", '∼'),
+    ("simeq", '≃'),
+    ("cong", '≅'),
+    ("equiv", '≡'),
+    ("propto", '∝'),
+    ("perp", '⊥'),
+    ("parallel", '∥'),
+    ("mid", '∣'),
+    ("in", '∈'),
+    ("notin", '∉'),
+    ("ni", '∋'),
+    ("subset", '⊂'),
+    ("supset", '⊃'),
+    ("subseteq", '⊆'),
+    ("supseteq", '⊇'),
+    ("to", '→'),
+    ("rightarrow", '→'),
+    ("gets", '←'),
+    ("leftarrow", '←'),
+    ("leftrightarrow", '↔'),
+    ("Rightarrow", '⇒'),
+    ("Leftarrow", '⇐'),
+    ("Leftrightarrow", '⇔'),
+    ("implies", '⟹'),
+    ("iff", '⟺'),
+    ("mapsto", '↦'),
+    ("longrightarrow", '⟶'),
+    ("longleftarrow", '⟵'),
+    ("uparrow", '↑'),
+    ("downarrow", '↓'),
+    ("forall", '∀'),
+    ("exists", '∃'),
+    ("nexists", '∄'),
+    ("ldots", '…'),
+    ("dots", '…'),
+    ("cdots", '⋯'),
+    ("vdots", '⋮'),
+    ("ddots", '⋱'),
+    ("colon", ':'),
+    ("langle", '⟨'),
+    ("rangle", '⟩'),
+    ("lfloor", '⌊'),
+    ("rfloor", '⌋'),
+    ("lceil", '⌈'),
+    ("rceil", '⌉'),
+    ("vert", '|'),
+    ("lvert", '|'),
+    ("rvert", '|'),
+    ("Vert", '‖'),
+    ("lVert", '‖'),
+    ("rVert", '‖'),
+    ("backslash", '\\'),
+    ("prime", '′'),
+];
+
+/// Large operators, with whether their scripts are limits
+const LARGE_OPERATORS: &[(&str, char, bool)] = &[
+    ("sum", '∑', true),
+    ("prod", '∏', true),
+    ("coprod", '∐', true),
+    ("bigcup", '⋃', true),
+    ("bigcap", '⋂', true),
+    ("bigoplus", '⨁', true),
+    ("bigotimes", '⨂', true),
+    ("bigvee", '⋁', true),
+    ("bigwedge", '⋀', true),
+    ("int", '∫', false),
+    ("iint", '∬', false),
+    ("iiint", '∭', false),
+    ("oint", '∮', false),
+];
+
+/// Named functions, with whether their scripts are limits
+const FUNCTIONS: &[(&str, &str, bool)] = &[
+    ("arccos", "arccos", false),
+    ("arcsin", "arcsin", false),
+    ("arctan", "arctan", false),
+    ("arg", "arg", false),
+    ("cos", "cos", false),
+    ("cosh", "cosh", false),
+    ("cot", "cot", false),
+    ("coth", "coth", false),
+    ("csc", "csc", false),
+    ("deg", "deg", false),
+    ("dim", "dim", false),
+    ("exp", "exp", false),
+    ("hom", "hom", false),
+    ("ker", "ker", false),
+    ("lg", "lg", false),
+    ("ln", "ln", false),
+    ("log", "log", false),
+    ("sec", "sec", false),
+    ("sin", "sin", false),
+    ("sinh", "sinh", false),
+    ("tan", "tan", false),
+    ("tanh", "tanh", false),
+    ("det", "det", true),
+    ("gcd", "gcd", true),
+    ("inf", "inf", true),
+    ("lim", "lim", true),
+    ("liminf", "lim inf", true),
+    ("limsup", "lim sup", true),
+    ("max", "max", true),
+    ("min", "min", true),
+    ("Pr", "Pr", true),
+    ("sup", "sup", true),
+];
+
+/// Greek letters, symbols, operators and named functions
+fn symbol(name: &str, variant: Option<Variant>) -> Option<Atom> {
+    if let Some((_, c)) = IDENTIFIERS.iter().find(|(symbol, _)| *symbol == name) {
+        let upright = name.starts_with(char::is_uppercase) && c.is_alphabetic();
+        return Some(Atom::new(if upright && variant.is_none() {
+            format!(r#"<mi mathvariant="normal">{}</mi>"#, c)
+        } else {
+            identifier(*c, variant)
+        }));
+    }
+    if let Some((_, c)) = OPERATORS.iter().find(|(symbol, _)| *symbol == name) {
+        return Some(Atom::new(format!("<mo>{}</mo>", escape(&c.to_string()))));
+    }
+    if let Some((_, c, limits)) = LARGE_OPERATORS.iter().find(|(symbol, ..)| *symbol == name) {
+        let movable = if *limits {
+            r#" movablelimits="true""#
+        } else {
+            ""
+        };
+        return Some(Atom {
+            mathml: format!(r#"<mo largeop="true"{}>{}</mo>"#, movable, c),
+            limits: *limits,
+            function: false,
+        });
+    }
+    if let Some((_, text, limits)) = FUNCTIONS.iter().find(|(function, ..)| *function == name) {
+        return Some(if *limits {
+            Atom {
+                mathml: format!(r#"<mo movablelimits="true" form="prefix">{}</mo>"#, text),
+                limits: true,
+                function: false,
+            }
+        } else {
+            Atom {
+                mathml: format!("<mi>{}</mi>", text),
+                limits: false,
+                function: true,
+            }
+        });
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every supported construct besides the symbols, with its MathML
+    const SUPPORTED: &[(&str, &str)] = &[
+        ("x + 3.14 - y", "<mi>x</mi><mo>+</mo><mn>3.14</mn><mo>−</mo><mi>y</mi>"),
+        ("a < b", "<mi>a</mi><mo>&lt;</mo><mi>b</mi>"),
+        ("{x}", "<mi>x</mi>"),
+        ("x % comment\n y", "<mi>x</mi><mi>y</mi>"),
+        (r"\alpha \Gamma \infty", r#"<mi>α</mi><mi mathvariant="normal">Γ</mi><mi>∞</mi>"#),
+        ("x_1", "<msub><mi>x</mi><mn>1</mn></msub>"),
+        ("x^2", "<msup><mi>x</mi><mn>2</mn></msup>"),
+        ("x_i^2", "<msubsup><mi>x</mi><mi>i</mi><mn>2</mn></msubsup>"),
+        ("x^23", "<msup><mi>x</mi><mn>2</mn></msup><mn>3</mn>"),
+        ("f''", "<msup><mi>f</mi><mo>&#x2032;&#x2032;</mo></msup>"),
+        ("f'^2", "<msup><mi>f</mi><mrow><mo>&#x2032;</mo><mn>2</mn></mrow></msup>"),
+        ("^2", "<msup><mrow></mrow><mn>2</mn></msup>"),
+        (r"\sum_{i=1}^n i", r#"<munderover><mo largeop="true" movablelimits="true">∑</mo><mrow><mi>i</mi><mo>=</mo><mn>1</mn></mrow><mi>n</mi></munderover><mi>i</mi>"#),
+        (r"\int_0^1 x", r#"<msubsup><mo largeop="true">∫</mo><mn>0</mn><mn>1</mn></msubsup><mi>x</mi>"#),
+        (r"\sin x", "<mi>sin</mi><mo>&#x2061;</mo><mi>x</mi>"),
+        (r"\lim_n a", r#"<munder><mo movablelimits="true" form="prefix">lim</mo><mi>n</mi></munder><mi>a</mi>"#),
+        (r"\frac{a}{b}", "<mfrac><mi>a</mi><mi>b</mi></mfrac>"),
+        (r"\binom{n}{k}", r#"<mrow><mo>(</mo><mfrac linethickness="0"><mi>n</mi><mi>k</mi></mfrac><mo>)</mo></mrow>"#),
+        (r"\sqrt{x}", "<msqrt><mi>x</mi></msqrt>"),
+        (r"\sqrt[3]{x}", "<mroot><mi>x</mi><mn>3</mn></mroot>"),
+        (r"\left( x \right)", r#"<mrow><mo fence="true" stretchy="true">(</mo><mi>x</mi><mo fence="true" stretchy="true">)</mo></mrow>"#),
+        (r"\left\{ x \right.", r#"<mrow><mo fence="true" stretchy="true">{</mo><mi>x</mi></mrow>"#),
+        (r"\left\langle x \right|", r#"<mrow><mo fence="true" stretchy="true">&#x27E8;</mo><mi>x</mi><mo fence="true" stretchy="true">&#x7C;</mo></mrow>"#),
+        (r"\text{if } x", "<mtext>if </mtext><mi>x</mi>"),
+        (r"\mbox{a\_b}", "<mtext>a&#x5F;b</mtext>"),
+        (r"\operatorname{sgn} x", "<mi>sgn</mi><mo>&#x2061;</mo><mi>x</mi>"),
+        (r"\mathrm{d}x", r#"<mi mathvariant="normal">d</mi><mi>x</mi>"#),
+        (r"\mathbf{v1}", "<mrow><mi>𝐯</mi><mn>𝟏</mn></mrow>"),
+        (r"\mathbb{R}", "<mi>ℝ</mi>"),
+        (r"\mathcal{L}", "<mi>ℒ</mi>"),
+        (r"\hat x", r#"<mover accent="true"><mi>x</mi><mo>&#x5E;</mo></mover>"#),
+        (r"\tilde x", r#"<mover accent="true"><mi>x</mi><mo>&#x2DC;</mo></mover>"#),
+        (r"\bar x", r#"<mover accent="true"><mi>x</mi><mo>&#xAF;</mo></mover>"#),
+        (r"\vec x", r#"<mover accent="true"><mi>x</mi><mo>&#x2192;</mo></mover>"#),
+        (r"\dot x", r#"<mover accent="true"><mi>x</mi><mo>&#x2D9;</mo></mover>"#),
+        (r"\ddot x", r#"<mover accent="true"><mi>x</mi><mo>&#xA8;</mo></mover>"#),
+        (r"\overline{x}", r#"<mover accent="true"><mi>x</mi><mo stretchy="true">&#x203E;</mo></mover>"#),
+        (r"\underline{x}", r#"<munder accentunder="true"><mi>x</mi><mo stretchy="true">&#x5F;</mo></munder>"#),
+        ("a~b", r#"<mi>a</mi><mspace width="0.333em"></mspace><mi>b</mi>"#),
+        (r"a\,b", r#"<mi>a</mi><mspace width="0.167em"></mspace><mi>b</mi>"#),
+        (r"a\:b", r#"<mi>a</mi><mspace width="0.222em"></mspace><mi>b</mi>"#),
+        (r"a\;b", r#"<mi>a</mi><mspace width="0.278em"></mspace><mi>b</mi>"#),
+        (r"a\!b", r#"<mi>a</mi><mspace width="-0.167em"></mspace><mi>b</mi>"#),
+        (r"a\ b", r#"<mi>a</mi><mspace width="0.333em"></mspace><mi>b</mi>"#),
+        (r"a\quad b", r#"<mi>a</mi><mspace width="1em"></mspace><mi>b</mi>"#),
+        (r"a\qquad b", r#"<mi>a</mi><mspace width="2em"></mspace><mi>b</mi>"#),
+        (r"\{ \} \% \$ \& \# \_ \|", "<mo>{</mo><mo>}</mo><mo>%</mo><mo>&#x24;</mo><mo>&amp;</mo><mo>&#x23;</mo><mo>&#x5F;</mo><mo>&#x2016;</mo>"),
+        (r"x \nonumber", "<mi>x</mi>"),
+        (r"\begin{matrix} a & b \end{matrix}", r#"<mtable><mtr><mtd columnalign="center"><mi>a</mi></mtd><mtd columnalign="center"><mi>b</mi></mtd></mtr></mtable>"#),
+        (r"\begin{pmatrix} a \\ b \\ \end{pmatrix}", r#"<mrow><mo fence="true" stretchy="true">(</mo><mtable><mtr><mtd columnalign="center"><mi>a</mi></mtd></mtr><mtr><mtd columnalign="center"><mi>b</mi></mtd></mtr></mtable><mo fence="true" stretchy="true">)</mo></mrow>"#),
+        (r"\begin{bmatrix} a \end{bmatrix}", r#"<mrow><mo fence="true" stretchy="true">[</mo><mtable><mtr><mtd columnalign="center"><mi>a</mi></mtd></mtr></mtable><mo fence="true" stretchy="true">]</mo></mrow>"#),
+        (r"\begin{cases} a & x \end{cases}", r#"<mrow><mo fence="true" stretchy="true">{</mo><mtable><mtr><mtd columnalign="left"><mi>a</mi></mtd><mtd columnalign="left"><mi>x</mi></mtd></mtr></mtable></mrow>"#),
+        (r"\begin{aligned} a &= b \end{aligned}", r#"<mtable displaystyle="true"><mtr><mtd columnalign="right"><mi>a</mi></mtd><mtd columnalign="left"><mo>=</mo><mi>b</mi></mtd></mtr></mtable>"#),
+    ];
+
+    /// Constructs which are rendered to svg instead, with the reason
+    const UNSUPPORTED: &[(&str, &str)] = &[
+        (r"\unknown", r"\unknown"),
+        (r"\displaystyle x", r"\displaystyle"),
+        (r"\mathfrak{g}", r"\mathfrak"),
+        (r"\begin{tikzpicture}", r"\begin{tikzpicture}"),
+        (r"\begin{matrix} a \end{pmatrix}", r"\end{pmatrix}"),
+        (r"\left( x", r"\left without \right"),
+        (r"\left\lfloor x \right\rfloor", r"delimiter \lfloor"),
+        (r"\text{$x$}", r"\text{$x$}"),
+        (r"\frac{a}", "missing argument"),
+        ("x_1_2", "double script"),
+        ("{x", "missing `}`"),
+        (r"\sqrt[3", "missing `]`"),
+        (r"\sqrt[3{x}", "missing `]`"),
+        ("x}", "}"),
+        ("a & b", "&"),
+        (r"a \\ b", r"\\"),
+    ];
+
+    fn math(tex: &str) -> String {
+        let math = convert(tex, false, &BTreeMap::new()).unwrap();
+        math.strip_prefix(r#"<math xmlns="http://www.w3.org/1998/Math/MathML">"#)
+            .and_then(|math| math.strip_suffix("</math>"))
+            .unwrap()
+            .to_owned()
+    }
+
+    #[test]
+    fn supported() {
+        for &(tex, mathml) in SUPPORTED {
+            assert_eq!(math(tex), mathml, "{}", tex);
+        }
+    }
+
+    #[test]
+    fn symbols() {
+        let symbols = IDENTIFIERS
+            .iter()
+            .chain(OPERATORS)
+            .map(|&(name, c)| (name, c.to_string()))
+            .chain(
+                LARGE_OPERATORS
+                    .iter()
+                    .map(|&(name, c, _)| (name, c.to_string())),
+            )
+            .chain(
+                FUNCTIONS
+                    .iter()
+                    .map(|&(name, text, _)| (name, text.to_owned())),
+            );
+        for (name, symbol) in symbols {
+            let math = math(&format!("\\{}", name));
+            assert!(math.contains(&escape(&symbol)), "{}: {}", name, math);
+        }
+    }
+
+    #[test]
+    fn unsupported() {
+        for &(tex, reason) in UNSUPPORTED {
+            assert_eq!(
+                convert(tex, false, &BTreeMap::new()),
+                Err(Unsupported(reason.to_owned())),
+                "{}",
+                tex
+            );
+        }
+    }
+
+    #[test]
+    fn macros() {
+        let macros = BTreeMap::from_iter([
+            ("R".to_owned(), r"\mathbb{R}".to_owned()),
+            ("abs".to_owned(), r"\left|#1\right|".to_owned()),
+            ("loop".to_owned(), r"\loop".to_owned()),
+        ]);
+        assert_eq!(
+            convert(r"\abs{x} \in \R", false, &macros).unwrap(),
+            r#"<math xmlns="http://www.w3.org/1998/Math/MathML"><mrow><mo fence="true" stretchy="true">&#x7C;</mo><mi>x</mi><mo fence="true" stretchy="true">&#x7C;</mo></mrow><mo>∈</mo><mi>ℝ</mi></math>"#
+        );
+        assert_eq!(
+            convert(r"\loop", false, &macros),
+            Err(Unsupported("recursive macro".to_owned()))
+        );
+    }
+
+    #[test]
+    fn markdown_safe() {
+        let math = convert(r"a_b * \text{x_y [z]}", true, &BTreeMap::new()).unwrap();
+        assert!(math.contains(r#"display="block""#));
+        for special in ['*', '_', '[', ']', '`'] {
+            assert!(!math.contains(special), "{} in {}", special, math);
+        }
+    }
+}
@@ -1,39 +1,219 @@
 use super::*;
+use regex::Regex;
+use std::collections::HashSet;
+use std::sync::OnceLock;
 
-pub fn format_figure<'a>(
-    replacement: &Replacement<'a>,
+use crate::bibliography::escape_html;
+
+/// The `<object>`, `<img>` or inline `<svg>` element showing a rendered fragment.
+///
+/// Empty for the latex renderers, which typeset the source themselves.
+pub fn embed_svg(
+    ctx: &mut BookContext<'_>,
+    chapter: &ChapterInfo,
+    replacement: &Replacement<'_>,
+    class: Option<&str>,
+) -> Result<String> {
+    use SupportedRenderer::*;
+    if let Latex | Tectonic = ctx.renderer {
+        return Ok(String::new());
+    }
+
+    let file = asset_url(
+        chapter.path.as_deref(),
+        &ctx.config.assets_output,
+        &replacement.svg,
+    );
+    let mut class_attr = class
+        .map(|class| format!(r#" class="{}""#, class))
+        .unwrap_or_default();
+    // inline equations sit on the baseline of the surrounding text
+    if let Some(metrics) = replacement.metrics {
+        class_attr += &format!(r#" style="vertical-align: {}""#, metrics.vertical_align());
+    }
+    Ok(match ctx.config.embed {
+        Embed::Object => {
+            format!(r#"<object{class_attr} data="{file}" type="image/svg+xml"></object>"#)
+        }
+        Embed::Img => format!(
+            r#"<img{class_attr} src="{file}" alt="{alt}">"#,
+            alt = escape_html(replacement.content.s.trim())
+        ),
+        Embed::Inline => {
+            let svg = fs::read_to_string(ctx.fragment_path.join(&replacement.svg))?;
+            ctx.embedded += 1;
+            inline_svg(&svg, &format!("sci{}-", ctx.embedded), &class_attr)
+        }
+    })
+}
+
+/// Url of the copied `file` in the assets output directory `dir`, relative to the rendered
+/// chapter `from`
+pub fn asset_url(from: Option<&Path>, dir: &Path, file: &Path) -> String {
+    let depth = from
+        .and_then(Path::parent)
+        .map(|parent| parent.components().count())
+        .unwrap_or_default();
+    let mut url = "../".repeat(depth);
+    for component in dir.join(file).components() {
+        url.push_str(&component.as_os_str().to_string_lossy());
+        url.push('/');
+    }
+    url.pop();
+    url
+}
+
+/// Math typeset by KaTeX or MathJax in the browser.
+///
+/// Escaped, so Markdown neither removes the backslashes nor emphasizes anything.
+pub fn client_math(tex: &str, display: bool) -> String {
+    // an empty line would end the html block
+    let tex = tex
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .join("\n");
+    if display {
+        format!(
+            r#"<div class="math display">{}</div>"#,
+            crate::mathml::escape(&format!("\\[{}\\]", tex))
+        )
+    } else {
+        format!(
+            r#"<span class="math inline">{}</span>"#,
+            crate::mathml::escape(&format!("\\({}\\)", tex))
+        )
+    }
+}
+
+/// The `<script>` of the `client` backend, `script` is relative to the `src` directory
+/// unless it is a url
+pub fn client_script(script: &str, chapter: &ChapterInfo) -> String {
+    let src = if script.contains("://") || script.starts_with('/') {
+        script.to_owned()
+    } else {
+        asset_url(chapter.path.as_deref(), Path::new(""), Path::new(script))
+    };
+    format!(r#"<script src="{}"></script>"#, escape_html(&src))
+}
+
+/// Prepare a standalone svg to be part of a page.
+///
+/// Ids, classes and font families are prefixed, since the fonts of each svg are subsets
+/// and would otherwise clash with the ones of other svgs on the same page.
+fn inline_svg(svg: &str, prefix: &str, class_attr: &str) -> String {
+    static ID: OnceLock<Regex> = OnceLock::new();
+    static CLASS: OnceLock<Regex> = OnceLock::new();
+    static SELECTOR: OnceLock<Regex> = OnceLock::new();
+    static REFERENCE: OnceLock<Regex> = OnceLock::new();
+    static FONT: OnceLock<Regex> = OnceLock::new();
+    let id = ID.get_or_init(|| Regex::new(r#"\bid=(["'])([^"']+)["']"#).unwrap());
+    let class = CLASS.get_or_init(|| Regex::new(r#"\bclass=(["'])([^"']+)["']"#).unwrap());
+    let selector = SELECTOR.get_or_init(|| Regex::new(r"\.([A-Za-z_][\w-]*)").unwrap());
+    let reference = REFERENCE.get_or_init(|| Regex::new(r#"(["'(])#([^"')\s]+)"#).unwrap());
+    let font =
+        FONT.get_or_init(|| Regex::new(r#"(font-family\s*[:=]\s*["']?)([^;}"']+)"#).unwrap());
+
+    // drop the xml declaration, doctype and comments in front of the root element
+    let svg = svg.find("<svg").map(|start| &svg[start..]).unwrap_or(svg);
+
+    let ids = HashSet::<&str>::from_iter(
+        id.captures_iter(svg)
+            .filter_map(|c| c.get(2))
+            .map(|m| m.as_str()),
+    );
+    let classes = HashSet::<&str>::from_iter(
+        class
+            .captures_iter(svg)
+            .filter_map(|c| c.get(2))
+            .flat_map(|m| m.as_str().split_whitespace()),
+    );
+
+    let svg = id.replace_all(svg, |c: &regex::Captures| {
+        format!("id={}{}{}{}", &c[1], prefix, &c[2], &c[1])
+    });
+    let svg = reference.replace_all(&svg, |c: &regex::Captures| {
+        if ids.contains(&c[2]) {
+            format!("{}#{}{}", &c[1], prefix, &c[2])
+        } else {
+            c[0].to_owned()
+        }
+    });
+    let svg = class.replace_all(&svg, |c: &regex::Captures| {
+        let prefixed = c[2]
+            .split_whitespace()
+            .map(|class| format!("{}{}", prefix, class))
+            .join(" ");
+        format!("class={}{}{}", &c[1], prefixed, &c[1])
+    });
+    let svg = selector.replace_all(&svg, |c: &regex::Captures| {
+        if classes.contains(&c[1]) {
+            format!(".{}{}", prefix, &c[1])
+        } else {
+            c[0].to_owned()
+        }
+    });
+    let svg = font.replace_all(&svg, |c: &regex::Captures| {
+        format!("{}{}{}", &c[1], prefix, &c[2])
+    });
+
+    // the root element carries the class of the replaced `<object>`
+    svg.trim_end()
+        .replacen("<svg", &format!("<svg{}", class_attr), 1)
+}
+
+pub fn format_figure(
+    source: &str,
+    image: &str,
     refer: &str,
-    head_num: &str,
-    figures_counter: usize,
+    number: &str,
     title: &str,
+    width: Option<&str>,
     renderer: SupportedRenderer,
 ) -> String {
     use SupportedRenderer::*;
     match renderer {
         Html | Markdown => {
+            let style = width
+                .map(|width| format!(r#" style="width: {}""#, escape_html(width)))
+                .unwrap_or_default();
             format!(
-                r#"<figure id="{refer}" class="figure">
-                    <object data="assets/{file}" type="image/svg+xml"/></object>
-                    <figcaption>Figure {head_num}{figures_counter} {title}</figcaption>
+                r#"<figure id="{refer}" class="figure"{style}>
+                    {image}
+                    <figcaption>Figure {number} {title}</figcaption>
                 </figure>"#,
-                refer = refer,
-                head_num = head_num,
-                figures_counter = figures_counter,
-                title = title,
-                file = replacement.svg.display()
+                refer = escape_html(refer),
+                number = number,
+                title = escape_html(title),
+                image = image,
+                style = style
             )
         }
         Latex | Tectonic => {
-            format!(r#"\[{}\]"#, replacement.intermediate())
+            format!(r#"\[{}\]"#, source)
         }
     }
 }
 
-pub fn format_equation_block<'a>(
-    replacement: &Replacement<'a>,
+/// Placeholder of a construct which failed, showing the first line of `err`
+pub fn format_error(err: &Error, renderer: SupportedRenderer) -> String {
+    use SupportedRenderer::*;
+    let message = err.to_string();
+    let summary = message.lines().next().unwrap_or_default();
+    match renderer {
+        Html | Markdown => format!(
+            r#"<span class="scientific_error" title="{}">Error: {}</span>"#,
+            escape_html(&message),
+            escape_html(summary)
+        ),
+        Latex | Tectonic => format!("**Error: {}**", summary),
+    }
+}
+
+pub fn format_equation_block(
+    source: &str,
+    image: &str,
     refer: &str,
-    head_num: &str,
-    equations_counter: usize,
+    number: &str,
     renderer: SupportedRenderer,
 ) -> String {
     use SupportedRenderer::*;
@@ -42,50 +222,41 @@ pub fn format_equation_block<'a>(
             format!(
                 r#"<div id="{refer}" class="equation">
                     <div class="equation_inner">
-                        <object data="assets/{file}" type="image/svg+xml"></object>
-                    </div><span>({head_num}{equations_counter})</span>
+                        {image}
+                    </div><span>({number})</span>
                 </div>"#,
-                refer = refer,
-                head_num = head_num,
-                equations_counter = equations_counter,
-                file = replacement.svg.display()
+                refer = escape_html(refer),
+                number = number,
+                image = image
             )
         }
         Latex | Tectonic => {
-            format!(r#"\[{}\]"#, replacement.intermediate())
+            format!(r#"\[{}\]"#, source)
         }
     }
 }
 
-pub fn format_equation<'a>(replacement: &Replacement<'a>, renderer: SupportedRenderer) -> String {
+pub fn format_equation(source: &str, image: &str, renderer: SupportedRenderer) -> String {
     use SupportedRenderer::*;
     match renderer {
         Html | Markdown => {
             format!(
-                r#"<div class="equation"><div class="equation_inner"><object data="assets/{file}" type="image/svg+xml"></object></div></div>\n"#,
-                file = replacement.svg.display()
+                "<div class=\"equation\"><div class=\"equation_inner\">{image}</div></div>\n",
+                image = image
             )
         }
         Latex | Tectonic => {
-            format!(r#"\[{}\]"#, replacement.intermediate())
+            format!(r#"\[{}\]"#, source)
         }
     }
 }
 
-pub fn format_inline_equation<'a>(
-    replacement: &Replacement<'a>,
-    renderer: SupportedRenderer,
-) -> String {
+pub fn format_inline_equation(source: &str, image: &str, renderer: SupportedRenderer) -> String {
     use SupportedRenderer::*;
     match renderer {
-        Html | Markdown => {
-            format!(
-                r#"<object class="equation_inline" data="assets/{file}" type="image/svg+xml"></object>"#,
-                file = replacement.svg.display()
-            )
-        }
+        Html | Markdown => image.to_owned(),
         Latex | Tectonic => {
-            format!(r#"${}$"#, replacement.content.s)
+            format!(r#"${}$"#, source)
         }
     }
 }
@@ -0,0 +1,325 @@
+use super::*;
+
+/// Keys of attributes accepted in any form of a block header
+const ATTRIBUTES: [&str; 3] = ["caption", "width", "preamble"];
+
+/// Header of a `$$` block, the remainder of the line of the opening `$$`.
+///
+/// Accepted are the comma form `gnuplot, <name>, <title>`, the attribute form
+/// `gnuplot {#fig:<name> caption="<title>" width=80%}` and the legacy form
+/// `ref:gnuplot:<name>:<title>`. Attributes like `preamble=physics` may follow the comma and
+/// legacy forms, values containing commas, spaces or `=` are quoted with `"` or `'`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct BlockHeader<'a> {
+    pub(super) kind: BlockKind,
+    pub(super) refer: Option<&'a str>,
+    pub(super) title: Option<&'a str>,
+    /// Width of a figure, as a css length
+    pub(super) width: Option<&'a str>,
+    /// Preamble profiles used by this block
+    pub(super) preambles: Vec<&'a str>,
+}
+
+impl<'a> BlockHeader<'a> {
+    fn unnamed() -> Self {
+        Self {
+            kind: BlockKind::Equation,
+            refer: None,
+            title: None,
+            width: None,
+            preambles: Vec::new(),
+        }
+    }
+
+    /// Split the content of a block starting at `lico` into header and body.
+    ///
+    /// Only a first line starting with a kind of block, `ref:`, an attribute or a `{#..}`
+    /// is a header, otherwise all of it is the body.
+    pub(super) fn parse(dollarless: &'a str, lico: LiCo) -> Result<(Self, &'a str)> {
+        let (line, body) = dollarless.split_once('\n').unwrap_or(("", dollarless));
+        let line = line.trim();
+        let invalid = |reason: String| Error::InvalidBlockHeader {
+            reason,
+            location: Location::at(lico),
+        };
+
+        if let Some(legacy) = line.strip_prefix("ref:") {
+            return Self::parse_legacy(legacy)
+                .map(|header| (header, body))
+                .map_err(invalid);
+        }
+
+        let (line, braced) = split_braced(line).map_err(invalid)?;
+        let fields = if line.is_empty() {
+            Vec::new()
+        } else {
+            split_unquoted(line, |c| c == ',').map_err(invalid)?
+        };
+        let is_header = braced.is_some()
+            || fields
+                .first()
+                .copied()
+                .and_then(BlockKind::from_name)
+                .is_some()
+            || fields
+                .iter()
+                .filter_map(|field| attribute(field))
+                .any(|(key, _)| ATTRIBUTES.contains(&key));
+        if !is_header {
+            return Ok((Self::unnamed(), dollarless));
+        }
+
+        let mut header = Self::unnamed();
+        let mut positional = Vec::new();
+        for field in fields {
+            match attribute(field) {
+                Some((key, value)) => header.set(key, value).map_err(invalid)?,
+                None => positional.push(unquote(field)),
+            }
+        }
+        match positional[..] {
+            [] => {}
+            [kind, ref rest @ ..] => {
+                header.kind = BlockKind::from_name(kind)
+                    .ok_or_else(|| invalid(format!("unknown kind of block `{}`", kind)))?;
+                match rest {
+                    [] => {}
+                    [name] => header.refer = Some(*name).filter(|name| !name.is_empty()),
+                    [name, title] => {
+                        header.refer = Some(*name).filter(|name| !name.is_empty());
+                        header.title = Some(*title);
+                    }
+                    [_, _, unexpected, ..] => {
+                        return Err(invalid(format!(
+                            "unexpected `{}`, titles containing commas must be quoted",
+                            unexpected
+                        )))
+                    }
+                }
+            }
+        }
+
+        if let Some(tokens) = braced {
+            header.set_braced(tokens).map_err(invalid)?;
+        }
+        Ok((header, body))
+    }
+
+    /// Extend the header by the `{..}` attributes in `braced`, which follow a block or a
+    /// figure in Pandoc syntax
+    pub(super) fn with_attributes(mut self, braced: &'a str, lico: LiCo) -> Result<Self> {
+        let invalid = |reason: String| Error::InvalidBlockHeader {
+            reason,
+            location: Location::at(lico),
+        };
+        match split_braced(braced.trim()).map_err(invalid)? {
+            ("", Some(tokens)) => self.set_braced(tokens).map_err(invalid)?,
+            _ => {
+                return Err(invalid(format!(
+                    "expected `{{#..}}`, got `{}`",
+                    braced.trim()
+                )))
+            }
+        }
+        Ok(self)
+    }
+
+    /// Header consisting of the `{..}` attributes in `braced` only
+    pub(super) fn attributes(braced: &'a str, lico: LiCo) -> Result<Self> {
+        Self::unnamed().with_attributes(braced, lico)
+    }
+
+    fn set_braced(&mut self, tokens: Vec<&'a str>) -> std::result::Result<(), String> {
+        for token in tokens {
+            if let Some(id) = token.strip_prefix('#') {
+                if self.refer.is_some() {
+                    return Err(format!("more than one name given by `{}`", token));
+                }
+                let name = strip_label_prefix(id);
+                if name.is_empty() {
+                    return Err(format!("empty name `{}`", token));
+                }
+                self.refer = Some(name);
+            } else if let Some((key, value)) = attribute(token) {
+                self.set(key, value)?;
+            } else {
+                return Err(format!("unexpected `{}` in `{{..}}`", token));
+            }
+        }
+        Ok(())
+    }
+
+    /// The legacy `[<kind>:]<name>[:<title>]` after `ref:`, the title may contain `:` and `,`.
+    ///
+    /// Without a kind it names an equation, the kind `equation` or `equ` alone is an unnamed one.
+    fn parse_legacy(legacy: &'a str) -> std::result::Result<Self, String> {
+        let mut header = Self::unnamed();
+        // attributes are trailing, titles may contain commas themselves
+        let mut legacy = legacy;
+        while let Some((rest, (key, value))) = legacy
+            .rsplit_once(',')
+            .and_then(|(rest, last)| attribute(last).map(|attribute| (rest, attribute)))
+            .filter(|(_, (key, _))| ATTRIBUTES.contains(key))
+        {
+            header.set(key, value)?;
+            legacy = rest.trim_end();
+        }
+        header.preambles.reverse();
+
+        let elms = Vec::from_iter(legacy.splitn(3, ':'));
+        match &elms[..] {
+            [kind @ ("latex" | "gnuplot" | "gnuplotonly"), refer, title] if !refer.is_empty() => {
+                header.kind = BlockKind::from_name(kind).expect("Matched a known kind. qed");
+                header.refer = Some(refer);
+                header.title = Some(title);
+            }
+            // a bare kind is an unnamed equation
+            ["equation" | "equ"] => {}
+            ["equation" | "equ", refer] | [refer] if !refer.is_empty() => {
+                header.refer = Some(refer)
+            }
+            _ => {
+                return Err(format!(
+                    "expected `ref:[equ:]<name>` or `ref:<kind>:<name>:<title>`, got `ref:{}`",
+                    legacy
+                ))
+            }
+        }
+        Ok(header)
+    }
+
+    fn set(&mut self, key: &str, value: &'a str) -> std::result::Result<(), String> {
+        match key {
+            "caption" => self.title = Some(value),
+            "width" => self.width = Some(value),
+            "preamble" => self.preambles.push(value),
+            _ => {
+                return Err(format!(
+                    "unknown attribute `{}`, values containing `=` must be quoted",
+                    key
+                ))
+            }
+        }
+        Ok(())
+    }
+}
+
+impl BlockKind {
+    /// Kind of the label a named block defines
+    pub(super) fn label_kind(self) -> LabelKind {
+        match self {
+            BlockKind::Equation => LabelKind::Equation,
+            _ => LabelKind::Figure,
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "equation" | "equ" => BlockKind::Equation,
+            "latex" => BlockKind::Latex,
+            "gnuplot" => BlockKind::Gnuplot,
+            "gnuplotonly" => BlockKind::GnuplotOnly,
+            _ => return None,
+        })
+    }
+}
+
+/// Name of a label without the `fig:` or `eq:` prefix of Pandoc syntax
+pub(super) fn strip_label_prefix(label: &str) -> &str {
+    ["fig:", "eq:", "equ:"]
+        .iter()
+        .find_map(|prefix| label.strip_prefix(prefix))
+        .unwrap_or(label)
+}
+
+/// Key and unquoted value of an attribute `key=value`, `None` if it is none
+fn attribute(field: &str) -> Option<(&str, &str)> {
+    let (key, value) = field.split_once('=')?;
+    let key = key.trim();
+    let is_key = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    is_key.then(|| (key, unquote(value)))
+}
+
+/// Strip the quotes of a trimmed value, if any
+fn unquote(value: &str) -> &str {
+    let value = value.trim();
+    ['"', '\'']
+        .iter()
+        .find_map(|quote| {
+            value
+                .strip_prefix(*quote)
+                .and_then(|value| value.strip_suffix(*quote))
+        })
+        .unwrap_or(value)
+}
+
+/// Split at all separators outside of quotes, pieces are trimmed and empty ones omitted
+/// if the separator is whitespace
+fn split_unquoted(
+    s: &str,
+    is_separator: fn(char) -> bool,
+) -> std::result::Result<Vec<&str>, String> {
+    let mut pieces = Vec::new();
+    let mut quote = None;
+    let mut start = 0;
+    for (idx, c) in s.char_indices() {
+        match quote {
+            Some(open) if c == open => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if is_separator(c) => {
+                pieces.push(s[start..idx].trim());
+                start = idx + c.len_utf8();
+            }
+            None => {}
+        }
+    }
+    if let Some(open) = quote {
+        return Err(format!("unterminated quote `{}`", open));
+    }
+    pieces.push(s[start..].trim());
+    if is_separator(' ') {
+        pieces.retain(|piece| !piece.is_empty());
+    }
+    Ok(pieces)
+}
+
+/// Split off a trailing `{..}` attribute block, which must start with `#` or an attribute.
+///
+/// Returns the remainder and the whitespace separated tokens within the braces.
+fn split_braced(line: &str) -> std::result::Result<(&str, Option<Vec<&str>>), String> {
+    let mut quote = None;
+    let mut open = None;
+    for (idx, c) in line.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '{' && open.is_none() => {
+                let preceding = line[..idx].chars().next_back();
+                if preceding.is_none_or(|c| c.is_whitespace() || c == ',') {
+                    open = Some(idx);
+                }
+            }
+            None => {}
+        }
+    }
+    let Some(open) = open else {
+        return Ok((line, None));
+    };
+    let inner = line[open + 1..].trim_start();
+    let is_attributes = inner.starts_with('#')
+        || attribute(inner).is_some_and(|(key, _)| ATTRIBUTES.contains(&key));
+    if !is_attributes {
+        return Ok((line, None));
+    }
+    let Some(inner) = inner.strip_suffix('}') else {
+        return Err("unterminated `{`, attributes must end the line".to_owned());
+    };
+    let rest = line[..open].trim_end();
+    let rest = rest.strip_suffix(',').unwrap_or(rest).trim_end();
+    Ok((rest, Some(split_unquoted(inner, char::is_whitespace)?)))
+}
@@ -6,30 +6,18 @@ fn create_svg_from_mermaid(
     chapterno: &str,
     counter: usize,
 ) -> Result<PathBuf> {
-    let mmdc = which::which("mmdc")?;
+    let mmdc = fragments::find_binary("mmdc")?;
     let dest = dest.as_ref();
 
     let dest = dest.join(format!("mermaid_{}_{}.svg", chapterno, counter));
 
-    let mut child = std::process::Command::new(mmdc)
-        .arg("--outputFormat=svg")
-        .arg(format!("--output={}", dest.display()))
-        .stdin(std::process::Stdio::piped())
-        .stdout(std::process::Stdio::piped())
-        .spawn()?;
-
-    // FIXME make this simpler
-    let code = code.to_owned();
-    let mut stdin = child.stdin.take().unwrap();
-    let j = std::thread::spawn(move || {
-        stdin.write(code.as_bytes())?;
-        Ok::<_, crate::errors::Error>(())
-    });
-    let mut stdout = child.stdout.unwrap();
-    let mut buf = String::with_capacity(8192);
-    stdout.read_to_string(&mut buf)?;
-
-    j.join().unwrap()?;
+    let mut cmd = std::process::Command::new(mmdc);
+    cmd.arg("--outputFormat=svg")
+        .arg(format!("--output={}", dest.display()));
+    let output = fragments::run(&mut cmd, Some(code.as_bytes()))?;
+    if !output.status.success() {
+        return Err(fragments::failed(&cmd, &output));
+    }
 
     Ok(dest)
 }
@@ -37,12 +25,17 @@ fn create_svg_from_mermaid(
 /// Replaces the content of the cmark file where codeblocks tagged with `mermaid`
 /// so for
 ///
+/// The charts are written to `dest`, the assets output directory `assets_output` within `src`,
+/// and linked relative to the chapter at `path`. The paths of the rendered charts are appended
+/// to `charts`.
 pub fn replace_mermaid_charts(
     source: &str,
     chapterno: String,
+    path: Option<&Path>,
     dest: impl AsRef<Path>,
+    assets_output: &Path,
     renderer: SupportedRenderer,
-    used_fragments: &mut Vec<PathBuf>,
+    charts: &mut Vec<PathBuf>,
 ) -> Result<String> {
     match renderer {
         // html can just fine deal with it
@@ -55,7 +48,6 @@ pub fn replace_mermaid_charts(
     let dest = dest.as_ref();
 
     use pulldown_cmark::*;
-    use pulldown_cmark_to_cmark::cmark;
 
     let mut buf = String::with_capacity(source.len());
 
@@ -67,7 +59,7 @@ pub fn replace_mermaid_charts(
 
     let mut events = vec![];
     let mut state = State::default();
-    for (event, offset) in Parser::new_ext(&source, Options::all()).into_offset_iter() {
+    for event in Parser::new_ext(source, Options::all()) {
         match event {
             Event::Start(Tag::CodeBlock(ref kind)) => match kind {
                 CodeBlockKind::Fenced(s) if s.as_ref() == "mermaid" => {
@@ -85,38 +77,40 @@ pub fn replace_mermaid_charts(
                 _ => {}
             },
 
-            Event::Text(ref code) | Event::Code(ref code) => {
-                if state.is_mermaid_block {
-                    let svg_path = create_svg_from_mermaid(
-                        code.as_ref(),
-                        dest,
-                        chapterno.as_str(),
-                        state.counter,
-                    )?;
-                    used_fragments.push(svg_path.clone());
-
-                    let desc: CowStr =
-                        format!("Chapter {}, Graphic {}", chapterno.as_str(), state.counter).into();
-                    let title = desc.clone();
-                    let inject = Tag::Image(
-                        LinkType::Inline,
-                        svg_path.display().to_string().into(),
-                        title,
-                    );
-
-                    events.push(Event::Start(inject.clone()));
-                    events.push(Event::Text(desc));
-                    events.push(Event::End(inject));
-                    continue;
-                }
+            Event::Text(ref code) | Event::Code(ref code) if state.is_mermaid_block => {
+                let svg_path = create_svg_from_mermaid(
+                    code.as_ref(),
+                    dest,
+                    chapterno.as_str(),
+                    state.counter,
+                )?;
+                let file = svg_path
+                    .file_name()
+                    .map(Path::new)
+                    .expect("Rendered charts are named. qed");
+                let url = asset_url(path, assets_output, file);
+                charts.push(svg_path.clone());
+
+                let desc: CowStr =
+                    format!("Chapter {}, Graphic {}", chapterno.as_str(), state.counter).into();
+                let title = desc.clone();
+                let inject = Tag::Image(
+                    LinkType::Inline,
+                    url.into(),
+                    title,
+                );
+
+                events.push(Event::Start(inject.clone()));
+                events.push(Event::Text(desc));
+                events.push(Event::End(inject));
+                continue;
             }
             _ => {}
         }
         events.push(event);
     }
 
-    pulldown_cmark_to_cmark::cmark(dbg!(events).into_iter(), &mut buf)
-        .map_err(Error::CommonMarkGlue)?;
+    pulldown_cmark_to_cmark::cmark(events.into_iter(), &mut buf).map_err(Error::CommonMarkGlue)?;
     Ok(buf)
 }
 
@@ -130,7 +124,7 @@ mod tests {
 
     #[test]
     fn gen_mermaid_svg_and_replace() {
-        let dest = temp_dir().join(format!("mdboff"));
+        let dest = temp_dir().join("mdboff");
         fs::create_dir_all(&dest).unwrap();
         let adjusted = replace_mermaid_charts(
             r#"
@@ -140,16 +134,20 @@ graph
 ```
 "#,
             "1.2.3".into(),
+            Some(Path::new("part/chapter.md")),
             dest,
+            Path::new("assets"),
             SupportedRenderer::Markdown,
             &mut Vec::new(),
         )
         .unwrap();
 
-        let mut iter = Parser::new_ext(&adjusted, Options::all()).into_iter();
+        let mut iter = Parser::new_ext(&adjusted, Options::all());
 
         let _ = iter.next();
-        assert_matches!(dbg!(iter.next()), Some(Event::Start(Tag::Image(_, _, _))));
+        assert_matches!(dbg!(iter.next()), Some(Event::Start(Tag::Image(_, url, _))) => {
+            assert_eq!(url.as_ref(), "../assets/mermaid_1.2.3_1.svg");
+        });
         assert_matches!(iter.next(), Some(Event::Text(s)) => {
             assert!(s.contains("1.2.3"));
         });
@@ -1,11 +1,13 @@
 use fs_err as fs;
 use itertools::Itertools;
-use sha2::Digest;
 use std::collections::HashMap;
-use std::io::{Read, Write};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use crate::errors::{Error, Result};
+use crate::bibliography::{Citations, BIBLIOGRAPHY_PATH};
+use crate::cache::Cache;
+use crate::errors::{Error, Location, Result};
 use crate::fragments;
 use crate::types::*;
 
@@ -18,12 +20,21 @@ pub use self::mermaid::*;
 mod format;
 pub use self::format::*;
 
+mod references;
+pub use self::references::*;
+
+mod header;
+use self::header::BlockHeader;
+
+mod pandoc;
+use self::pandoc::*;
+
 #[cfg(test)]
 mod tests;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 struct SplitTagPosition<'a> {
-    /// Position in line + columns
+    /// Position of the first character of the delimiter
     lico: LiCo,
     /// Offset in bytes from the beginning of the string
     byte_offset: usize,
@@ -31,122 +42,217 @@ struct SplitTagPosition<'a> {
     which: Dollar<'a>,
 }
 
-fn dollar_split_tags_iter<'a>(source: &'a str) -> impl Iterator<Item = SplitTagPosition<'a>> {
+/// Markdown extensions, aligned with what `mdbook` enables for rendering.
+fn markdown_options() -> pulldown_cmark::Options {
+    use pulldown_cmark::Options;
+    Options::ENABLE_TABLES
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_HEADING_ATTRIBUTES
+}
+
+/// Byte ranges of `source` which are prose and hence may contain math.
+///
+/// Code spans, code blocks, html and link destinations are never part of it.
+/// Adjacent ranges are merged.
+fn prose_ranges(source: &str) -> Vec<Range<usize>> {
+    use pulldown_cmark::{Event, Parser, Tag};
+
+    let mut ranges = Vec::<Range<usize>>::new();
     let mut is_code_block = false;
-    let mut is_pre_block = false;
+    for (event, range) in Parser::new_ext(source, markdown_options()).into_offset_iter() {
+        match event {
+            Event::Start(Tag::CodeBlock(_)) => is_code_block = true,
+            Event::End(Tag::CodeBlock(_)) => is_code_block = false,
+            Event::Text(_) if !is_code_block => match ranges.last_mut() {
+                Some(last) if last.end == range.start => last.end = range.end,
+                _ => ranges.push(range),
+            },
+            _ => {}
+        }
+    }
+    ranges
+}
+
+/// Maps byte offsets to line and column.
+struct LineIndex<'a> {
+    source: &'a str,
+    /// Byte offset of the first character of each line
+    line_starts: Vec<usize>,
+}
+
+impl<'a> LineIndex<'a> {
+    fn new(source: &'a str) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(offset, _)| offset + 1))
+            .collect();
+        Self {
+            source,
+            line_starts,
+        }
+    }
+
+    fn lineno(&self, byte_offset: usize) -> usize {
+        self.line_starts
+            .partition_point(|&start| start <= byte_offset)
+            .saturating_sub(1)
+    }
+
+    /// The line containing `byte_offset`, without the trailing newline.
+    fn line_range(&self, byte_offset: usize) -> Range<usize> {
+        let lineno = self.lineno(byte_offset);
+        let start = self.line_starts[lineno];
+        let end = self
+            .line_starts
+            .get(lineno + 1)
+            .map(|next| next - 1)
+            .unwrap_or(self.source.len());
+        start..end
+    }
+
+    /// Base 1 line and column of `byte_offset`
+    fn lico(&self, byte_offset: usize) -> LiCo {
+        let lineno = self.lineno(byte_offset);
+        let line_start = self.line_starts[lineno];
+        LiCo {
+            lineno: lineno + 1,
+            column: self.source[line_start..byte_offset].chars().count() + 1,
+        }
+    }
+
+    /// Only whitespace, blockquote or list markers precede `byte_offset` in its line.
+    fn is_line_start(&self, byte_offset: usize) -> bool {
+        let line = self.line_range(byte_offset);
+        let prefix = &self.source[line.start..byte_offset];
+        // a list marker must be followed by whitespace
+        let is_list_marker = |marker: &str| {
+            matches!(marker, "-" | "*" | "+")
+                || marker.strip_suffix(['.', ')']).is_some_and(|n| {
+                    (1..=9).contains(&n.len()) && n.bytes().all(|b| b.is_ascii_digit())
+                })
+        };
+        prefix
+            .split_whitespace()
+            .all(|marker| marker.chars().all(|c| c == '>') || is_list_marker(marker))
+            && !prefix.ends_with(|c: char| !c.is_whitespace() && c != '>')
+    }
+}
+
+/// Find all `$` and `$$` delimiters within the prose of a markdown document.
+///
+/// A `$$` at the beginning of a line opens a block, which spans until the next `$$`.
+/// Inline `$` must be closed within the same line. Like in Pandoc, an opening `$` must not be
+/// followed by whitespace and a closing `$` neither follow whitespace nor be followed by a digit,
+/// so amounts like `$5` stay prose. A `$` after whitespace which could open instead replaces the
+/// pending opening one.
+/// Escaped `\$` are never delimiters, unmatched ones are kept as they are and added to
+/// `unmatched`, along with a block which is closed at the end of the document. Amounts,
+/// unmatched ones followed by a digit, are not added.
+/// Dollar signs within `opaque` byte ranges, e.g. the captions of Pandoc figures, are prose.
+fn dollar_split_tags_iter<'a>(
+    source: &'a str,
+    opaque: &[Range<usize>],
+    unmatched: &mut Vec<SplitTagPosition<'a>>,
+) -> impl Iterator<Item = SplitTagPosition<'a>> {
+    let index = LineIndex::new(source);
+
+    let tag = |byte_offset: usize, which: Dollar<'a>| SplitTagPosition {
+        lico: index.lico(byte_offset),
+        byte_offset,
+        which,
+    };
+    let is_escaped = |byte_offset: usize| {
+        source[..byte_offset]
+            .bytes()
+            .rev()
+            .take_while(|&b| b == b'\\')
+            .count()
+            % 2
+            == 1
+    };
+    let followed_by = |byte_offset: usize, f: fn(char) -> bool| {
+        source[byte_offset + INLINE_BLOCK_DELIM.len()..]
+            .chars()
+            .next()
+            .is_some_and(f)
+    };
+    let is_amount =
+        |tag: &SplitTagPosition<'a>| followed_by(tag.byte_offset, |c| c.is_ascii_digit());
+    let preceded_by_whitespace = |byte_offset: usize| {
+        source[..byte_offset]
+            .chars()
+            .next_back()
+            .is_some_and(char::is_whitespace)
+    };
+    let mut tags = Vec::<SplitTagPosition<'a>>::new();
     let mut is_dollar_block = false;
-    source
-        .lines()
-        .scan(0_usize, |state, line_content| {
-            let previous_line_char_count = *state;
-            let current_char_count = line_content.chars().count();
-            *state = current_char_count;
-            Some((previous_line_char_count, current_char_count, line_content))
-            // provide the previous line length and the current
-        })
-        .enumerate()
-        .scan(
-            0,
-            move |state, (lineno, (previous_char_cnt, current_char_cnt, line_content))| {
-                // handle block content
-
-                let byte_offset = *state;
-                *state += current_char_cnt + 1;
-
-                // the end of the previous line
-                let _previous = LiCo {
-                    lineno: lineno.saturating_sub(1),
-                    column: previous_char_cnt,
-                };
-                let mut current = LiCo { lineno, column: 1 };
+    // whether the last tag is a currently open inline `$`
+    let mut open_inline = false;
 
-                // FIXME NOT OK, could also be further in
-                if line_content.starts_with("<pre") {
-                    is_pre_block = true;
-                    return None;
-                }
+    for range in prose_ranges(source) {
+        let mut offset = range.start;
+        while let Some(found) = source[offset..range.end].find(INLINE_BLOCK_DELIM) {
+            let byte_offset = offset + found;
+            offset = byte_offset + INLINE_BLOCK_DELIM.len();
+            if is_escaped(byte_offset) || opaque.iter().any(|range| range.contains(&byte_offset)) {
+                continue;
+            }
+            let is_double = source[byte_offset..range.end].starts_with(BLOCK_DELIM);
 
-                if line_content.starts_with("</pre>") {
-                    is_pre_block = false;
-                    return None;
+            if open_inline {
+                let opened_at = tags.last().expect("An open inline `$` is tagged. qed");
+                if index.lineno(opened_at.byte_offset) != index.lineno(byte_offset) {
+                    unmatched.extend(tags.pop().filter(|tag| !is_amount(tag)));
+                    open_inline = false;
                 }
+            }
 
-                if is_pre_block {
-                    return None;
+            let delim = if is_dollar_block {
+                if !is_double {
+                    continue;
                 }
-
-                // FIXME use a proper markdown/commonmark parser, it's unfixable this
-                // way i.e pre start and end in one line or multiple..
-                if line_content.starts_with("```") {
-                    is_code_block = !is_code_block;
+                is_dollar_block = false;
+                Dollar::End(&source[byte_offset..][..BLOCK_DELIM.len()])
+            } else if is_double && !open_inline && index.is_line_start(byte_offset) {
+                is_dollar_block = true;
+                Dollar::Start(&source[byte_offset..][..BLOCK_DELIM.len()])
+            } else if open_inline {
+                if followed_by(byte_offset, |c| c.is_ascii_digit()) {
+                    continue;
                 }
-                if is_code_block {
-                    return None;
+                if preceded_by_whitespace(byte_offset) {
+                    // `pay $5 or more $x$`, the pending `$` is no delimiter after all
+                    if !followed_by(byte_offset, |c| !c.is_whitespace()) {
+                        continue;
+                    }
+                    unmatched.extend(tags.pop().filter(|tag| !is_amount(tag)));
+                    Dollar::Start(&source[byte_offset..][..INLINE_BLOCK_DELIM.len()])
+                } else {
+                    open_inline = false;
+                    Dollar::End(&source[byte_offset..][..INLINE_BLOCK_DELIM.len()])
                 }
-
-                if line_content.starts_with("$$") {
-                    is_dollar_block = !is_dollar_block;
-                    return Some(
-                        vec![SplitTagPosition {
-                            which: if is_dollar_block {
-                                Dollar::Start(&line_content[..("$$".len())])
-                            } else {
-                                Dollar::End(&line_content[..("$$".len())])
-                            },
-                            lico: current,
-                            byte_offset,
-                            // char_offset, // TODO
-                        }]
-                        .into_iter(),
-                    );
+            } else {
+                if !followed_by(byte_offset, |c| !c.is_whitespace()) {
+                    continue;
                 }
+                open_inline = true;
+                Dollar::Start(&source[byte_offset..][..INLINE_BLOCK_DELIM.len()])
+            };
+            offset = byte_offset + delim.as_ref().len();
+            tags.push(tag(byte_offset, delim));
+        }
+    }
 
-                let mut is_intra_inline_code = false;
-                let mut is_between_dollar_content = false;
-
-                // use to collect ranges
-                let mut v = Vec::from_iter(line_content.char_indices().enumerate().filter_map(
-                    |(il_char_offset, (il_byte_offset, c))| {
-                        match c {
-                            '$' if !is_intra_inline_code => {
-                                is_between_dollar_content = !is_between_dollar_content;
-                                current.column = il_char_offset;
-                                let dollar = SplitTagPosition {
-                                    which: if is_between_dollar_content {
-                                        Dollar::Start(&line_content[il_byte_offset..][..1])
-                                    } else {
-                                        Dollar::End(&line_content[il_byte_offset..][..1])
-                                    },
-                                    lico: current,
-                                    byte_offset: byte_offset + il_byte_offset,
-                                };
-                                return Some(dollar);
-                            }
-                            '`' => {
-                                is_intra_inline_code = !is_intra_inline_code;
-                            }
-                            _ => {}
-                        }
-                        None
-                    },
-                ));
-
-                if v.len() & 0x1 != 0 {
-                    let last = v.last().unwrap();
-                    eprintln!("Inserting $-sign at end of line #{lineno}!");
-                    v.push(SplitTagPosition {
-                        lico: LiCo {
-                            lineno,
-                            column: current_char_cnt + 1,
-                        },
-                        byte_offset: line_content.len(),
-                        which: Dollar::End(""),
-                    })
-                }
-                Some(v.into_iter())
-            },
-        )
-        .flatten()
+    if open_inline {
+        unmatched.extend(tags.pop().filter(|tag| !is_amount(tag)));
+    }
+    if is_dollar_block {
+        unmatched.extend(tags.last().copied());
+        tags.push(tag(source.len(), Dollar::End(&source[source.len()..])));
+    }
+
+    tags.into_iter()
 }
 
 #[derive(Debug, Clone)]
@@ -155,11 +261,11 @@ enum Tagged<'a> {
     Keep(Content<'a>),
 }
 
-impl<'a> Into<Content<'a>> for Tagged<'a> {
-    fn into(self) -> Content<'a> {
-        match self {
-            Self::Replace(c) => c,
-            Self::Keep(c) => c,
+impl<'a> From<Tagged<'a>> for Content<'a> {
+    fn from(tagged: Tagged<'a>) -> Self {
+        match tagged {
+            Tagged::Replace(c) => c,
+            Tagged::Keep(c) => c,
         }
     }
 }
@@ -173,276 +279,933 @@ impl<'a> AsRef<Content<'a>> for Tagged<'a> {
     }
 }
 
+/// Split `source` into alternating parts to keep and to replace, covering all of `source`.
 fn iter_over_dollar_encompassed_blocks<'a>(
     source: &'a str,
     iter: impl Iterator<Item = SplitTagPosition<'a>>,
 ) -> impl Iterator<Item = Tagged<'a>> {
-    // make sure the first part is kept if it doesn't start with a dollar sign
-    let mut iter = iter.peekable();
-    let pre = match iter.peek() {
-        Some(nxt) if dbg!(nxt.byte_offset) > 0 => {
-            let byte_range = 0..(nxt.byte_offset);
-            let s = &source[byte_range.clone()];
-            Some(dbg!(Tagged::Keep(Content {
-                // content without the $ delimiters FIXME
-                s,
-                start: LiCo {
-                    lineno: 0,
-                    column: 0,
-                },
-                end: nxt.lico,
-                byte_range,
-                delimiter: Dollar::Empty
-            })))
-        }
-        _ => None,
+    let index = LineIndex::new(source);
+    let keep = |byte_range: Range<usize>, delimiter: Dollar<'a>| {
+        Tagged::Keep(Content {
+            s: &source[byte_range.clone()],
+            start: index.lico(byte_range.start),
+            end: index.lico(byte_range.end),
+            byte_range,
+            delimiter,
+            container: "",
+        })
     };
-    let iter = iter.tuple_windows().enumerate().map(
-        move |(
-            idx,
-            (
-                start @ SplitTagPosition {
-                    byte_offset: start_byte_offset,
-                    which: start_which,
-                    ..
-                },
-                end @ SplitTagPosition {
-                    byte_offset: end_byte_offset,
-                    which: end_which,
-                    ..
-                },
-            ),
-        )| {
-            let replace = idx & 0x1 == 0;
-            let byte_range = if replace {
-                // replace must _include_ the `$`-signs
-                start_byte_offset..(end_byte_offset + end_which.as_ref().len())
-            } else {
-                // first character might not exist, so this was injected and hence
-                // would skip the first character
-                let skip_dollar = if start_byte_offset == 0 {
-                    0
+
+    let mut tagged = Vec::new();
+    let mut previous = None::<SplitTagPosition<'a>>;
+    for current in iter {
+        match previous.take() {
+            // make sure the first part is kept if it doesn't start with a dollar sign
+            None if current.byte_offset > 0 => {
+                tagged.push(keep(0..current.byte_offset, Dollar::Empty));
+            }
+            None => {}
+            Some(start) => {
+                let content = if let Dollar::Start(_) = start.which {
+                    // replace must _include_ the `$`-signs
+                    let byte_range =
+                        start.byte_offset..(current.byte_offset + current.which.as_ref().len());
+                    let container = if start.which.is_block() {
+                        &source[index.line_range(start.byte_offset).start..start.byte_offset]
+                    } else {
+                        ""
+                    };
+                    Tagged::Replace(Content {
+                        s: &source[byte_range.clone()],
+                        start: start.lico,
+                        end: current.lico,
+                        byte_range,
+                        delimiter: start.which,
+                        container,
+                    })
                 } else {
-                    {
-                        start_which.as_ref().len()
-                    }
+                    keep(
+                        (start.byte_offset + start.which.as_ref().len())..current.byte_offset,
+                        start.which,
+                    )
                 };
-                (start_byte_offset + skip_dollar)..end_byte_offset
-            };
+                if !content.as_ref().s.is_empty() {
+                    tagged.push(content);
+                }
+            }
+        }
+        previous = Some(current);
+    }
 
-            // not within, so just return a string
-            let content = Content {
-                // content without the $ delimiters FIXME
-                s: &source[byte_range.clone()],
-                start: start.lico,
-                end: end.lico,
-                byte_range,
-                delimiter: start.which,
-            };
+    // and the remainder after the last dollar sign
+    let tail_start = previous
+        .map(|last| last.byte_offset + last.which.as_ref().len())
+        .unwrap_or_default();
+    if tail_start < source.len() || source.is_empty() {
+        tagged.push(keep(
+            tail_start..source.len(),
+            previous.map(|last| last.which).unwrap_or(Dollar::Empty),
+        ));
+    }
 
-            if replace {
-                Tagged::Replace(content)
-            } else {
-                Tagged::Keep(content)
+    tagged.into_iter()
+}
+
+/// Parts of a chapter as split by [`iter_over_dollar_encompassed_blocks`].
+///
+/// Delimiters without a counterpart are added to `unmatched`, see [`dollar_split_tags_iter`].
+/// With Pandoc syntax the `{#..}` attributes following a `$$` block are attached to it and
+/// removed from the kept part after it, and the figures among `spans` are kept as a whole.
+fn tagged_with_attributes<'a>(
+    config: &ScientificConfig,
+    source: &'a str,
+    spans: &[PandocSpan<'_>],
+    unmatched: &mut Vec<SplitTagPosition<'a>>,
+) -> Vec<(Tagged<'a>, Option<&'a str>)> {
+    let index = LineIndex::new(source);
+    let figures = Vec::from_iter(spans.iter().filter_map(|span| match span.pandoc {
+        Pandoc::Figure { .. } => Some(span.byte_range.clone()),
+        _ => None,
+    }));
+    let iter = dollar_split_tags_iter(source, &figures, unmatched);
+    let mut parts = Vec::<(Tagged<'a>, Option<&'a str>)>::new();
+    for tagged in iter_over_dollar_encompassed_blocks(source, iter) {
+        if let (Tagged::Keep(content), Some((Tagged::Replace(block), attributes))) =
+            (&tagged, parts.last_mut())
+        {
+            if let (Syntax::Pandoc, true, None, Some(found)) = (
+                config.syntax,
+                block.delimiter.is_block(),
+                &attributes,
+                trailing_attributes(content.s),
+            ) {
+                *attributes = Some(found);
+                let start = content.byte_range.start + found.len();
+                if start < content.byte_range.end {
+                    parts.push((
+                        Tagged::Keep(Content {
+                            s: &content.s[found.len()..],
+                            start: index.lico(start),
+                            byte_range: start..content.byte_range.end,
+                            ..content.clone()
+                        }),
+                        None,
+                    ));
+                }
+                continue;
             }
+        }
+        parts.push((tagged, None));
+    }
+    parts
+}
+
+/// Pandoc constructs within the prose of a chapter, none with the native syntax
+fn pandoc_spans_of<'a>(config: &ScientificConfig, source: &'a str) -> Vec<PandocSpan<'a>> {
+    match config.syntax {
+        Syntax::Native => Vec::new(),
+        Syntax::Pandoc => pandoc_spans(source),
+    }
+}
+
+/// The spans lying within the kept part `content`
+fn spans_within<'s, 'a>(
+    spans: &'s [PandocSpan<'a>],
+    content: &Content<'_>,
+) -> impl Iterator<Item = &'s PandocSpan<'a>> {
+    let range = content.byte_range.clone();
+    spans.iter().filter(move |span| {
+        range.start <= span.byte_range.start && span.byte_range.end <= range.end
+    })
+}
+
+/// Strip the enclosing delimiters, the closing one might be absent if it was injected.
+fn strip_delimiters<'a>(content: &Content<'a>) -> Content<'a> {
+    let delimiter = content.delimiter.as_ref();
+    let content = content.tail(content.s.strip_prefix(delimiter).unwrap_or(content.s));
+    match content.s.strip_suffix(delimiter) {
+        Some(s) => Content {
+            s,
+            byte_range: content.byte_range.start..content.byte_range.end - delimiter.len(),
+            ..content
         },
-    );
-    pre.into_iter().chain(iter)
+        None => content,
+    }
 }
 
-pub fn replace_blocks(
-    fragment_path: impl AsRef<Path>,
-    asset_path: impl AsRef<Path>,
-    source: &str,
-    head_num: &str,
-    renderer: SupportedRenderer,
-    used_fragments: &mut Vec<PathBuf>,
-    references: &mut HashMap<String, String>,
-) -> Result<String> {
-    let fragment_path = fragment_path.as_ref();
-    fs::create_dir_all(fragment_path)?;
-
-    let iter = dollar_split_tags_iter(source);
-    let s = iter_over_dollar_encompassed_blocks(source, iter)
-        .map(|tagged| {
-            let content = tagged.as_ref();
-            // let mut dollarless_range = content.byte_range.clone();
-            let regex = regex::Regex::new(r###"^\$+(.+)\$+"###).unwrap();
-            let dollarless = regex.replace_all(content.as_ref(), "$1");
-            let mut content = content.clone();
-            // a bit bonkers FIXME XXX incoherent datastructure
-            content.s = dollarless.as_ref();
-
-            if !content.delimiter.is_block() {
-                transform_block_as_needed(
-                    &content,
-                    fragment_path,
-                    head_num,
-                    references,
-                    used_fragments,
-                    renderer,
-                )
-            } else {
-                transform_inline_as_needed(
-                    &content,
-                    fragment_path,
-                    head_num,
-                    references,
-                    used_fragments,
-                    renderer,
-                )
-            }
-        })
-        .collect::<Result<Vec<String>>>()?
-        .into_iter()
-        .join("\n");
-    Ok(s)
+/// State of the whole book, shared by all chapters
+pub struct BookContext<'a> {
+    pub config: &'a ScientificConfig,
+    /// Canonicalized directory of the intermediate fragments
+    pub fragment_path: PathBuf,
+    pub renderer: SupportedRenderer,
+    pub references: References,
+    pub counters: Counters,
+    pub citations: Citations,
+    /// Manifest of the fragments in `fragment_path`
+    pub cache: Cache,
+    /// Template of all equations
+    pub template: fragments::Template,
+    /// Fragments which have to be copied into the assets directory
+    pub used_fragments: Vec<PathBuf>,
+    /// Number of svgs inlined into the current chapter so far
+    pub embedded: usize,
+    /// Number of equations left to the browser in the current chapter so far
+    pub client_math: usize,
+    /// Errors of all chapters so far, the offending source is replaced by a placeholder
+    pub errors: Vec<Error>,
+    /// Errors of the fragments which failed to render ahead of the chapters, by name
+    pub failed: HashMap<String, Arc<Error>>,
 }
 
-fn transform_inline_as_needed<'a>(
-    dollarless: &Content<'a>,
-    fragment_path: impl AsRef<Path>,
-    head_num: &str,
-    references: &mut HashMap<String, String>,
-    used_fragments: &mut Vec<PathBuf>,
-    renderer: SupportedRenderer,
+impl BookContext<'_> {
+    /// Markup of an equation which is not rendered to svg, see [`markup`]
+    fn markup(&mut self, content: &Content<'_>, display: bool, preamble: &str) -> Option<String> {
+        let markup = markup(self.config, content, display, preamble)?.ok()?;
+        if self.config.math_backend == MathBackend::Client {
+            self.client_math += 1;
+        }
+        Some(markup)
+    }
+}
+
+/// Position of a chapter within the book
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChapterInfo {
+    /// Section number, empty for unnumbered chapters
+    pub number: Vec<u32>,
+    /// Source path, relative to the `src` directory
+    pub path: Option<PathBuf>,
+    /// Preamble profiles listed in the front matter, with their location
+    pub preambles: Vec<(String, LiCo)>,
+}
+
+impl ChapterInfo {
+    pub fn new(chapter: &mdbook::book::Chapter) -> Self {
+        Self {
+            number: chapter
+                .number
+                .as_ref()
+                .map(|number| number.0.clone())
+                .unwrap_or_default(),
+            path: chapter.path.clone(),
+            preambles: front_matter(&chapter.content)
+                .map(|(_, preambles)| preambles)
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Preamble of the profiles used by the chapter and additionally by a block at `lico`
+    fn preamble(&self, config: &ScientificConfig, block: &[&str], lico: LiCo) -> Result<String> {
+        let chapter = self
+            .preambles
+            .iter()
+            .map(|(name, lico)| (name.as_str(), *lico));
+        let block = block.iter().map(|name| (*name, lico));
+        profile_preamble(config, chapter.chain(block))
+    }
+}
+
+/// Front matter at the very beginning of a chapter, delimited by `---` lines.
+///
+/// Only `key: value` lines and a `preamble` key make it front matter, otherwise the first
+/// `---` is a thematic break of the prose. Returns its length in bytes and the profiles
+/// listed as `preamble: physics, chemistry`.
+fn front_matter(source: &str) -> Option<(usize, Vec<(String, LiCo)>)> {
+    let mut lines = source.split_inclusive('\n');
+    let first = lines.next()?;
+    if first.trim_end() != "---" {
+        return None;
+    }
+
+    let mut len = first.len();
+    let mut preambles = None::<Vec<_>>;
+    for (lineno, line) in (2..).zip(lines) {
+        len += line.len();
+        if line.trim_end() == "---" {
+            return preambles.map(|preambles| (len, preambles));
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (key, value) = line.split_once(':')?;
+        let is_key = !key.is_empty()
+            && key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !is_key {
+            return None;
+        }
+        if key == "preamble" {
+            let names = value.trim().trim_start_matches('[').trim_end_matches(']');
+            let offset = line.len() - value.len();
+            preambles.get_or_insert_with(Vec::new).extend(
+                names
+                    .split(',')
+                    .map(|name| name.trim().trim_matches(|c| c == '"' || c == '\''))
+                    .filter(|name| !name.is_empty())
+                    .map(|name| {
+                        let idx = offset + value.find(name).unwrap_or_default();
+                        let column = line[..idx].chars().count() + 1;
+                        (name.to_owned(), LiCo { lineno, column })
+                    }),
+            );
+        }
+    }
+    None
+}
+
+/// Replace the front matter with empty lines, so it is not rendered but line numbers stay
+fn blank_front_matter(source: &str) -> std::borrow::Cow<'_, str> {
+    match front_matter(source) {
+        Some((len, _)) => {
+            let lines = source[..len].matches('\n').count();
+            ("\n".repeat(lines) + &source[len..]).into()
+        }
+        None => source.into(),
+    }
+}
+
+/// Preamble of the profiles used by a chapter and a block, in order and without duplicates
+fn profile_preamble<'n>(
+    config: &ScientificConfig,
+    names: impl IntoIterator<Item = (&'n str, LiCo)>,
 ) -> Result<String> {
-    let fragment_path = fragment_path.as_ref();
-    let lineno = dollarless.start.lineno;
-    let content = dollarless;
+    let mut preamble = String::new();
+    for (name, lico) in names.into_iter().unique_by(|(name, _)| *name) {
+        let profile = config
+            .preambles
+            .get(name)
+            .ok_or_else(|| Error::UnknownPreamble {
+                name: name.to_owned(),
+                location: Location::at(lico),
+            })?;
+        preamble += &profile.latex();
+    }
+    Ok(preamble)
+}
 
-    let mut figures_counter = 0;
-    let mut equations_counter = 0;
+/// Kind of a `$$` block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlockKind {
+    Latex,
+    Gnuplot,
+    GnuplotOnly,
+    Equation,
+}
 
-    if let Some(stripped) = dollarless.strip_prefix("ref:") {
-        let mut add_object =
-            move |replacement: &Replacement<'_>, refer: &str, title: Option<&str>| -> String {
-                let file = replacement.svg.as_path();
-                used_fragments.push(file.to_owned());
-
-                if let Some(title) = title {
-                    figures_counter += 1;
-                    references.insert(
-                        refer.to_string(),
-                        format!("Figure {}{}", head_num, figures_counter),
-                    );
-
-                    format_figure(
-                        replacement,
-                        refer,
-                        head_num,
-                        figures_counter,
-                        title,
-                        renderer,
-                    )
-                } else if !refer.is_empty() {
-                    equations_counter += 1;
-                    references.insert(
-                        refer.to_string(),
-                        format!("{}{}", head_num, equations_counter),
-                    );
-                    format_equation_block(replacement, refer, head_num, equations_counter, renderer)
-                } else {
-                    format_equation(replacement, renderer)
+/// A fragment to be rendered to svg, detached from the chapter it is located in
+#[derive(Debug, Clone)]
+pub struct Fragment<'a> {
+    kind: BlockKind,
+    /// Body without delimiters and header
+    content: Content<'a>,
+    zoom: f32,
+    /// Preamble of the used profiles, only equations have one
+    preamble: String,
+    /// Equation within the text, typeset in text style on its baseline
+    inline: bool,
+    /// Where the content starts, errors of `latex` are located relative to it
+    origin: Location,
+}
+
+impl<'a> Fragment<'a> {
+    fn block(
+        config: &ScientificConfig,
+        kind: BlockKind,
+        content: Content<'a>,
+        preamble: String,
+        origin: Location,
+    ) -> Self {
+        let (zoom, preamble) = match kind {
+            BlockKind::Latex | BlockKind::Gnuplot | BlockKind::GnuplotOnly => {
+                (config.figure_zoom, String::new())
+            }
+            BlockKind::Equation => (config.block_zoom, preamble),
+        };
+        Self {
+            kind,
+            content,
+            zoom,
+            preamble,
+            inline: false,
+            origin,
+        }
+    }
+
+    fn inline(
+        config: &ScientificConfig,
+        content: Content<'a>,
+        preamble: String,
+        origin: Location,
+    ) -> Self {
+        Self {
+            kind: BlockKind::Equation,
+            content,
+            zoom: config.inline_zoom,
+            preamble,
+            inline: true,
+            origin,
+        }
+    }
+
+    /// Template of an equation, extended by the used profiles
+    fn template<'c>(&self, ctx: &'c BookContext<'_>) -> std::borrow::Cow<'c, fragments::Template> {
+        if self.preamble.is_empty() {
+            std::borrow::Cow::Borrowed(&ctx.template)
+        } else {
+            std::borrow::Cow::Owned(ctx.template.with(&self.preamble))
+        }
+    }
+
+    /// Name of the fragment within the cache, the svg is `<name>.svg`
+    fn name(&self, ctx: &BookContext<'_>) -> String {
+        let template = self.template(ctx).source();
+        const LATEX: &[&str] = &["latex", "dvisvgm"];
+        let (pipeline, tools, template) = match self.kind {
+            BlockKind::Latex => ("latex", LATEX, ""),
+            BlockKind::Gnuplot => ("gnuplot", &["gnuplot", "latex", "dvisvgm"][..], ""),
+            BlockKind::GnuplotOnly => ("gnuplotonly", &["gnuplot"][..], ""),
+            BlockKind::Equation if self.inline => ("inline", LATEX, template.as_str()),
+            BlockKind::Equation => ("equation", LATEX, template.as_str()),
+        };
+        ctx.cache.name(
+            tools,
+            &[pipeline, template, &self.zoom.to_string(), &self.content.math()],
+        )
+    }
+
+    /// Render the fragment, unless the cache holds a valid svg of it already or it failed to
+    /// render ahead of the chapters
+    fn render(&self, ctx: &BookContext<'_>) -> Result<Replacement<'a>> {
+        let name = self.name(ctx);
+        if let Some(err) = ctx.failed.get(&name) {
+            return Err(Error::Shared(err.clone()));
+        }
+        let cached = ctx.cache.prepare(&name)?;
+
+        let fragment_path = ctx.fragment_path.as_path();
+        let (content, origin) = (&self.content, &self.origin);
+        let mut replacement = match self.kind {
+            BlockKind::Latex => {
+                fragments::parse_latex(fragment_path, &name, content, origin, self.zoom)
+            }
+            BlockKind::Gnuplot => {
+                fragments::parse_gnuplot(fragment_path, &name, content, origin, self.zoom)
+            }
+            BlockKind::GnuplotOnly => fragments::parse_gnuplot_only(fragment_path, &name, content),
+            BlockKind::Equation => fragments::generate_replacement_file_from_template(
+                fragment_path,
+                &self.template(ctx),
+                &name,
+                content,
+                origin,
+                self.zoom,
+                self.inline,
+            ),
+        }?;
+
+        if cached {
+            replacement.metrics = ctx.cache.metrics(&name);
+        } else {
+            ctx.cache.record(&name, replacement.metrics)?;
+        }
+        Ok(replacement)
+    }
+}
+
+/// First pass over a chapter, registers the labels of all named blocks and all citations,
+/// including the ones not cited with `$ref:nocite:..$`, in order of appearance.
+///
+/// Must be run over all chapters before [`replace_blocks`], so references may point forward.
+pub fn collect_references(ctx: &mut BookContext<'_>, source: &str, chapter: &ChapterInfo) {
+    let spans = pandoc_spans_of(ctx.config, source);
+    for (tagged, attributes) in tagged_with_attributes(ctx.config, source, &spans, &mut Vec::new())
+    {
+        let content = match tagged {
+            Tagged::Replace(content) => strip_delimiters(&content),
+            Tagged::Keep(content) => {
+                for span in spans_within(&spans, &content) {
+                    collect_pandoc(ctx, span, source, chapter);
                 }
+                continue;
+            }
+        };
+        if content.delimiter.is_block() {
+            // malformed headers are reported when collecting fragments
+            let Ok((header, _body)) = block_header(&content, attributes) else {
+                continue;
             };
-
-        let elms = stripped.split(':').collect::<Vec<&str>>();
-        match &elms[..] {
-            ["latex", refer, title] => fragments::parse_latex(fragment_path, &content)
-                .map(|ref file| add_object(file, refer, Some(title))),
-            ["gnuplot", refer, title] => fragments::parse_gnuplot(fragment_path, &content)
-                .map(|ref file| add_object(file, refer, Some(title))),
-            ["gnuplotonly", refer, title] => fragments::parse_gnuplot_only(fragment_path, &content)
-                .map(|ref file| add_object(file, refer, Some(title))),
-
-            ["equation", refer] | ["equ", refer] => {
-                fragments::generate_replacement_file_from_template(fragment_path, &content, 1.6)
-                    .map(|ref file| add_object(file, refer, None))
+            let Some(refer) = header.refer else {
+                continue;
+            };
+            let kind = header.kind.label_kind();
+            let number = ctx.counters.next(kind, chapter, &ctx.config.numbering);
+            let location = Location::new(chapter.path.as_deref(), source, content.start);
+            let label = Label {
+                kind,
+                number,
+                chapter: chapter.path.clone(),
+            };
+            if let Err(err) = ctx.references.insert(refer, label, location) {
+                ctx.errors.push(err);
             }
+        } else if let Some(key) = content.strip_prefix("ref:bib:") {
+            // unknown keys are reported when resolving
+            let _ = ctx.citations.cite(key);
+        } else if let Some(key) = content.strip_prefix("ref:nocite:") {
+            let _ = ctx.citations.nocite(key);
+        }
+    }
+}
+
+/// Collect all fragments of a chapter which have to be rendered, in order of appearance.
+///
+/// Malformed blocks and unknown preamble profiles are skipped, they are reported when
+/// replacing.
+pub fn collect_fragments<'a>(
+    config: &ScientificConfig,
+    source: &'a str,
+    chapter: &ChapterInfo,
+    fragments: &mut Vec<Fragment<'a>>,
+) {
+    let origin =
+        |content: &Content<'_>| Location::new(chapter.path.as_deref(), source, content.start);
+    let Ok(chapter_preamble) = chapter.preamble(config, &[], LiCo::default()) else {
+        return;
+    };
 
-            ["equation"] | ["equ"] | _ => {
-                fragments::generate_replacement_file_from_template(fragment_path, &content, 1.6)
-                    .map(|ref file| add_object(file, "", None))
+    let spans = pandoc_spans_of(config, source);
+    for (tagged, attributes) in tagged_with_attributes(config, source, &spans, &mut Vec::new()) {
+        let Tagged::Replace(content) = tagged else {
+            continue;
+        };
+        let content = strip_delimiters(&content);
+        if content.delimiter.is_block() {
+            let Ok((header, body)) = block_header(&content, attributes) else {
+                continue;
+            };
+            let Ok(preamble) = chapter.preamble(config, &header.preambles, content.start) else {
+                continue;
+            };
+            let origin = origin(&body);
+            if header.kind != BlockKind::Equation
+                || needs_svg(config, &body, true, &preamble, &origin)
+            {
+                fragments.push(Fragment::block(config, header.kind, body, preamble, origin));
             }
+        } else if !content.starts_with("ref:") {
+            let origin = origin(&content);
+            if needs_svg(config, &content, false, &chapter_preamble, &origin) {
+                let preamble = chapter_preamble.clone();
+                fragments.push(Fragment::inline(config, content, preamble, origin));
+            }
+        }
+    }
+}
 
-            [kind, _] => Err(Error::UnknownReferenceKind {
-                kind: kind.to_owned().to_owned(),
-                lineno,
-            }),
-            _ => Err(Error::UnexpectedReferenceArgCount {
-                count: elms.len(),
-                lineno,
-            }),
+/// Markup of an equation which is not rendered to svg, `None` with the `svg` backend.
+///
+/// Profiles might load packages which change the meaning of anything, so equations using
+/// them are always rendered to svg by the `mathml` backend.
+fn markup(
+    config: &ScientificConfig,
+    content: &Content<'_>,
+    display: bool,
+    preamble: &str,
+) -> Option<std::result::Result<String, crate::mathml::Unsupported>> {
+    match config.math_backend {
+        MathBackend::Svg => None,
+        MathBackend::Mathml if !preamble.is_empty() => None,
+        MathBackend::Mathml => Some(crate::mathml::convert(
+            &content.math(),
+            display,
+            &config.macros,
+        )),
+        MathBackend::Client => Some(Ok(client_math(&content.math(), display))),
+    }
+}
+
+/// Whether an equation has to be rendered to svg, reports why the `mathml` backend can't
+/// convert it
+fn needs_svg(
+    config: &ScientificConfig,
+    content: &Content<'_>,
+    display: bool,
+    preamble: &str,
+    origin: &Location,
+) -> bool {
+    match markup(config, content, display, preamble) {
+        Some(Ok(_)) => false,
+        Some(Err(unsupported)) => {
+            eprintln!(
+                "Warning: Equation is rendered to svg, {}{}",
+                unsupported, origin
+            );
+            true
+        }
+        None => true,
+    }
+}
+
+/// Render all fragments of the book which are not cached yet, on up to `jobs` threads.
+///
+/// Identical fragments are rendered once. With `batch` enabled, equations of the same zoom
+/// are split into one batch per job, each rendered by a single `latex` run, the equations of
+/// a failed batch are rendered one by one. The errors of failed fragments are kept in
+/// `ctx.failed`, to be reported by the chapters using them.
+pub fn render_fragments(ctx: &mut BookContext<'_>, fragments: Vec<Fragment<'_>>) {
+    let pending = Vec::from_iter(
+        fragments
+            .into_iter()
+            .map(|fragment| (fragment.name(ctx), fragment))
+            .unique_by(|(name, _)| name.clone())
+            .filter(|(name, _)| !ctx.cache.is_valid(name)),
+    );
+    if pending.is_empty() {
+        return;
+    }
+
+    let jobs = ctx.config.jobs().min(pending.len());
+    eprintln!("Rendering {} fragments using {} jobs", pending.len(), jobs);
+
+    // units of work, either a single fragment or a batch of equations
+    let units = if ctx.config.batch {
+        let (equations, others) = pending
+            .into_iter()
+            .partition::<Vec<_>, _>(|(_, fragment)| fragment.kind == BlockKind::Equation);
+        let by_zoom = equations.into_iter().into_group_map_by(|(_, fragment)| {
+            (
+                fragment.zoom.to_bits(),
+                fragment.preamble.clone(),
+                fragment.inline,
+            )
+        });
+        let mut units = Vec::from_iter(others.into_iter().map(|fragment| vec![fragment]));
+        for equations in by_zoom.into_values() {
+            let size = equations.len().div_ceil(jobs);
+            for chunk in &equations.into_iter().chunks(size) {
+                units.push(chunk.collect());
+            }
         }
+        units
     } else {
-        fragments::generate_replacement_file_from_template(fragment_path, &dollarless, 1.3).map(
-            |replacement| {
-                let res = format_inline_equation(&replacement, renderer);
-                used_fragments.push(replacement.svg);
-                res
-            },
-        )
+        Vec::from_iter(pending.into_iter().map(|fragment| vec![fragment]))
+    };
+
+    let render = |unit: &[(String, Fragment<'_>)]| match unit {
+        [(_, fragment)] => fragment.render(ctx).map(|_| ()),
+        batch => {
+            let names = Vec::from_iter(batch.iter().map(|(name, _)| name.clone()));
+            let contents =
+                Vec::from_iter(batch.iter().map(|(_, fragment)| fragment.content.clone()));
+            let origins = Vec::from_iter(batch.iter().map(|(_, fragment)| fragment.origin.clone()));
+            for name in &names {
+                ctx.cache.prepare(name)?;
+            }
+            let metrics = fragments::generate_replacement_files_from_template_batch(
+                &ctx.fragment_path,
+                &batch[0].1.template(ctx),
+                &names,
+                &contents,
+                &origins,
+                batch[0].1.zoom,
+                batch[0].1.inline,
+            )?;
+            names
+                .iter()
+                .zip(metrics)
+                .try_for_each(|(name, metrics)| ctx.cache.record(name, metrics))
+        }
+    };
+
+    let failures = on_pool(jobs, &units, |unit| render(unit));
+
+    // a failed batch does not tell which of its equations are fine, so render them one by one
+    let (singles, batches) = failures
+        .into_iter()
+        .partition::<Vec<_>, _>(|(idx, _)| units[*idx].len() == 1);
+    let retries = Vec::from_iter(batches.iter().flat_map(|(idx, _)| &units[*idx]));
+    let retried = on_pool(jobs.min(retries.len()), &retries, |(_, fragment)| {
+        fragment.render(ctx).map(|_| ())
+    });
+
+    let singles = singles
+        .into_iter()
+        .map(|(idx, err)| (units[idx][0].0.clone(), err));
+    let retried = retried
+        .into_iter()
+        .map(|(idx, err)| (retries[idx].0.clone(), err));
+    for (name, err) in Vec::from_iter(singles.chain(retried)) {
+        ctx.failed.insert(name, Arc::new(err));
     }
 }
 
-/// `s` is the content withou
+/// Run `f` on all `units` using `jobs` threads, returns the errors by index of the unit
+fn on_pool<T: Sync>(
+    jobs: usize,
+    units: &[T],
+    f: impl Fn(&T) -> Result<()> + Sync,
+) -> Vec<(usize, Error)> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let next = AtomicUsize::new(0);
+    std::thread::scope(|scope| {
+        let workers = Vec::from_iter((0..jobs).map(|_| {
+            scope.spawn(|| {
+                let mut failures = Vec::new();
+                loop {
+                    let idx = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(unit) = units.get(idx) else {
+                        break;
+                    };
+                    if let Err(err) = f(unit) {
+                        failures.push((idx, err));
+                    }
+                }
+                failures
+            })
+        }));
+        Vec::from_iter(workers.into_iter().flat_map(|worker| {
+            worker
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+        }))
+    })
+}
+
+/// Second pass over a chapter, renders all math and resolves all references.
+///
+/// Whatever fails is replaced by a placeholder and its error added to `ctx.errors`.
+pub fn replace_blocks(
+    ctx: &mut BookContext<'_>,
+    source: &str,
+    chapter: &ChapterInfo,
+) -> Result<String> {
+    fs::create_dir_all(&ctx.fragment_path)?;
+    ctx.embedded = 0;
+    ctx.client_math = 0;
+
+    let original = source;
+    let source = &blank_front_matter(source);
+    let spans = pandoc_spans_of(ctx.config, source);
+    let mut unmatched = Vec::new();
+    let tagged = tagged_with_attributes(ctx.config, source, &spans, &mut unmatched);
+    for tag in unmatched {
+        let location = Location::new(chapter.path.as_deref(), source, tag.lico);
+        if tag.which.is_block() {
+            eprintln!(
+                "Warning: Unclosed `$$` block is closed at the end of the chapter{}",
+                location
+            );
+        } else {
+            eprintln!(
+                "Warning: Unmatched `$` is kept as is, escape it as `\\$` if intended{}",
+                location
+            );
+        }
+    }
+    let mut replaced = String::new();
+    for (tagged, attributes) in tagged {
+        let transformed = match tagged {
+            Tagged::Keep(content) => Ok(transform_prose(ctx, &content, &spans, chapter, original)),
+            Tagged::Replace(content) => {
+                let content = strip_delimiters(&content);
+                if !content.delimiter.is_block() {
+                    transform_block_as_needed(ctx, &content, chapter)
+                } else {
+                    transform_inline_as_needed(ctx, &content, attributes, chapter)
+                }
+            }
+        };
+        match transformed {
+            Ok(transformed) => replaced += &transformed,
+            // keep going, so all errors of the book are reported at once
+            Err(err) => {
+                let err = err.located(chapter.path.as_deref(), original);
+                replaced += &format_error(&err, ctx.renderer);
+                ctx.errors.push(err);
+            }
+        }
+    }
+
+    // only the html renderer runs scripts
+    if let (Some(script), 1.., SupportedRenderer::Html) =
+        (&ctx.config.client_script, ctx.client_math, ctx.renderer)
+    {
+        replaced += &format!("\n{}\n", client_script(script, chapter));
+    }
+    Ok(replaced)
+}
+
+/// Header and body of a `$$` block, extended by the `{#..}` attributes following it
+fn block_header<'a>(
+    dollarless: &Content<'a>,
+    attributes: Option<&'a str>,
+) -> Result<(BlockHeader<'a>, Content<'a>)> {
+    let (header, body) = BlockHeader::parse(dollarless.s, dollarless.start)?;
+    let header = match attributes {
+        Some(attributes) => header.with_attributes(attributes, dollarless.end)?,
+        None => header,
+    };
+    Ok((header, dollarless.tail(body)))
+}
+
+/// Kept prose, with the Pandoc constructs within it resolved
+///
+/// A construct that fails is replaced by a placeholder, the prose around it is kept.
+fn transform_prose(
+    ctx: &mut BookContext<'_>,
+    content: &Content<'_>,
+    spans: &[PandocSpan<'_>],
+    chapter: &ChapterInfo,
+    original: &str,
+) -> String {
+    let start = content.byte_range.start;
+    let mut prose = String::new();
+    let mut offset = start;
+    for span in spans_within(spans, content) {
+        prose += &content.s[offset - start..span.byte_range.start - start];
+        match transform_pandoc(ctx, span, chapter) {
+            Ok(transformed) => prose += &transformed,
+            Err(err) => {
+                let err = err.located(chapter.path.as_deref(), original);
+                prose += &format_error(&err, ctx.renderer);
+                ctx.errors.push(err);
+            }
+        }
+        offset = span.byte_range.end;
+    }
+    prose += &content.s[offset - start..];
+    prose
+}
+
+/// Renders a `$$` block, the first line is the header which may name the block
+fn transform_inline_as_needed<'a>(
+    ctx: &mut BookContext<'_>,
+    dollarless: &Content<'a>,
+    attributes: Option<&'a str>,
+    chapter: &ChapterInfo,
+) -> Result<String> {
+    let renderer = ctx.renderer;
+    let (header, content) = block_header(dollarless, attributes)?;
+    let preamble = chapter.preamble(ctx.config, &header.preambles, dollarless.start)?;
+
+    let math = match header.kind {
+        BlockKind::Equation => ctx.markup(&content, true, &preamble),
+        _ => None,
+    };
+    let (source, image) = match math {
+        Some(math) => (content.s.to_owned(), math),
+        None => {
+            let origin = Location::at(content.start);
+            let replacement =
+                Fragment::block(ctx.config, header.kind, content, preamble, origin).render(ctx)?;
+            ctx.used_fragments.push(replacement.svg.clone());
+            let image = embed_svg(ctx, chapter, &replacement, None)?;
+            (replacement.intermediate().to_owned(), image)
+        }
+    };
+
+    let label = header.refer.and_then(|refer| {
+        ctx.references
+            .get(header.kind.label_kind(), refer)
+            .map(|label| (refer, label))
+    });
+    Ok(match (label, header.kind) {
+        (Some((refer, label)), BlockKind::Equation) => {
+            format_equation_block(&source, &image, refer, &label.number, renderer)
+        }
+        (Some((refer, label)), _) => format_figure(
+            &source,
+            &image,
+            refer,
+            &label.number,
+            header.title.unwrap_or_default(),
+            header.width,
+            renderer,
+        ),
+        (None, _) => format_equation(&source, &image, renderer),
+    })
+}
+
+/// Renders inline `$` math, or resolves a `ref:` to a label defined anywhere in the book
 fn transform_block_as_needed<'a>(
+    ctx: &mut BookContext<'_>,
     dollarless: &Content<'a>,
-    fragment_path: impl AsRef<Path>,
-    head_num: &str,
-    references: &HashMap<String, String>,
-    used_fragments: &mut Vec<PathBuf>,
-    renderer: SupportedRenderer,
+    chapter: &ChapterInfo,
 ) -> Result<String> {
-    let fragment_path = fragment_path.as_ref();
-    let lineno = dollarless.start.lineno;
+    let lico = dollarless.start;
     if let Some(stripped) = dollarless.strip_prefix("ref:") {
         let elms = stripped.split(':').collect::<Vec<&str>>();
         match &elms[..] {
-            ["fig", refere] => references
-                .get::<str>(refere)
-                .ok_or(Error::InvalidReference {
-                    to: elms[1].to_owned(),
-                    lineno,
-                })
-                .map(|x| format!(r#"<a class="fig_ref" href='#{}'>{}</a>"#, elms[1], x)),
-            ["bib", refere] => references
-                .get::<str>(refere)
-                .ok_or(Error::InvalidReference {
-                    to: elms[1].to_owned(),
-                    lineno,
-                })
-                .map(|x| {
-                    format!(
-                        r#"<a class="bib_ref" href='bibliography.html#{}'>{}</a>"#,
-                        elms[1], x
-                    )
-                }),
-            ["equ", refere] => references
-                .get::<str>(refere)
-                .ok_or(Error::InvalidReference {
-                    to: elms[1].to_owned(),
-                    lineno,
-                })
-                .map(|x| format!(r#"<a class="equ_ref" href='#{}'>Eq. ({})</a>"#, elms[1], x)),
-
-            [kind, _] => Err(Error::UnknownReferenceKind {
-                kind: kind.to_owned().to_owned(),
-                lineno,
-            }),
+            [kind, refere] => reference(ctx, kind, refere, lico, chapter),
             _ => Err(Error::UnexpectedReferenceArgCount {
                 count: elms.len(),
-                lineno,
+                location: Location::at(lico),
             }),
         }
     } else {
-        fragments::generate_replacement_file_from_template(fragment_path, &dollarless, 1.3).map(
-            |replacement| {
-                let res = format_inline_equation(&replacement, renderer);
-                used_fragments.push(replacement.svg);
-                res
-            },
-        )
+        let preamble = chapter.preamble(ctx.config, &[], lico)?;
+        let image = match ctx.markup(dollarless, false, &preamble) {
+            Some(math) => math,
+            None => {
+                let origin = Location::at(lico);
+                let replacement =
+                    Fragment::inline(ctx.config, dollarless.clone(), preamble, origin)
+                        .render(ctx)?;
+                ctx.used_fragments.push(replacement.svg.clone());
+                embed_svg(ctx, chapter, &replacement, Some("equation_inline"))?
+            }
+        };
+        Ok(format_inline_equation(dollarless.s, &image, ctx.renderer))
+    }
+}
+
+/// Resolves a reference `$ref:<kind>:<refere>$` to a label defined anywhere in the book
+fn reference(
+    ctx: &mut BookContext<'_>,
+    kind: &str,
+    refere: &str,
+    lico: LiCo,
+    chapter: &ChapterInfo,
+) -> Result<String> {
+    let invalid = || Error::InvalidReference {
+        to: refere.to_owned(),
+        location: Location::at(lico),
+    };
+    let label_of = |kind| ctx.references.get(kind, refere).ok_or_else(invalid);
+    match kind {
+        "fig" => label_of(LabelKind::Figure).map(|label| {
+            format!(
+                r#"<a class="fig_ref" href='{}'>{}</a>"#,
+                label.href(refere, chapter),
+                label.text()
+            )
+        }),
+        "bib" => ctx.citations.cite(refere).ok_or_else(invalid).map(|x| {
+            format!(
+                r#"<a class="bib_ref" href='{}#{}'>{}</a>"#,
+                relative_url(chapter.path.as_deref(), Path::new(BIBLIOGRAPHY_PATH)),
+                refere,
+                x
+            )
+        }),
+        "nocite" => ctx
+            .citations
+            .nocite(refere)
+            .ok_or_else(invalid)
+            .map(|()| String::new()),
+        "equ" => label_of(LabelKind::Equation).map(|label| {
+            format!(
+                r#"<a class="equ_ref" href='{}'>Eq. ({})</a>"#,
+                label.href(refere, chapter),
+                label.text()
+            )
+        }),
+        kind => Err(Error::UnknownReferenceKind {
+            kind: kind.to_owned(),
+            location: Location::at(lico),
+        }),
     }
 }
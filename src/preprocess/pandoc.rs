@@ -0,0 +1,222 @@
+use super::*;
+use regex::Regex;
+use std::sync::OnceLock;
+
+use crate::bibliography::escape_html;
+
+/// A construct of the Pandoc syntax within the prose of a chapter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Pandoc<'a> {
+    /// `![caption](url){#fig:<name>}`, the caption as plain text
+    Figure {
+        caption: String,
+        url: String,
+        /// The image without the attributes
+        image: &'a str,
+        attributes: &'a str,
+    },
+    /// `@eq:<name>` or `@fig:<name>`
+    Reference(&'a str),
+    /// `[@key]` or `[@key; @eq:<name>]`, citations and references alike
+    Citation(Vec<&'a str>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct PandocSpan<'a> {
+    pub(super) byte_range: Range<usize>,
+    pub(super) lico: LiCo,
+    pub(super) pandoc: Pandoc<'a>,
+}
+
+/// All Pandoc constructs within the prose of `source`, in order of appearance
+pub(super) fn pandoc_spans(source: &str) -> Vec<PandocSpan<'_>> {
+    use pulldown_cmark::{Event, Parser, Tag};
+
+    static CITATION: OnceLock<Regex> = OnceLock::new();
+    static REFERENCE: OnceLock<Regex> = OnceLock::new();
+    let citation = CITATION
+        .get_or_init(|| Regex::new(r"\[\s*@[^\[\];\s]+\s*(?:;\s*@[^\[\];\s]+\s*)*\]").unwrap());
+    let reference =
+        REFERENCE.get_or_init(|| Regex::new(r"(?:^|[^\w@])(@(?:eq|fig):[\w-]+)").unwrap());
+
+    let index = LineIndex::new(source);
+    let mut spans = Vec::new();
+
+    let mut image = None::<(Range<usize>, String, String)>;
+    for (event, range) in Parser::new_ext(source, markdown_options()).into_offset_iter() {
+        match (event, image.as_mut()) {
+            (Event::Start(Tag::Image(_, url, _)), None) => {
+                image = Some((range, String::new(), url.to_string()))
+            }
+            (Event::Text(text) | Event::Code(text), Some((_, caption, _))) => {
+                caption.push_str(&text)
+            }
+            (Event::End(Tag::Image(..)), Some(_)) => {
+                let (range, caption, url) = image.take().expect("Matched an image. qed");
+                let Some(attributes) = source[range.end..]
+                    .starts_with('{')
+                    .then(|| trailing_attributes(&source[range.end..]))
+                    .flatten()
+                else {
+                    continue;
+                };
+                spans.push(PandocSpan {
+                    byte_range: range.start..range.end + attributes.len(),
+                    lico: index.lico(range.start),
+                    pandoc: Pandoc::Figure {
+                        caption,
+                        url,
+                        image: &source[range],
+                        attributes,
+                    },
+                });
+            }
+            _ => {}
+        }
+    }
+
+    for range in prose_ranges(source) {
+        let prose = &source[range.clone()];
+        let span = |found: regex::Match<'_>, pandoc| PandocSpan {
+            byte_range: range.start + found.start()..range.start + found.end(),
+            lico: index.lico(range.start + found.start()),
+            pandoc,
+        };
+        for found in citation.find_iter(prose) {
+            let keys = found.as_str()[1..found.as_str().len() - 1]
+                .split(';')
+                .map(|key| key.trim().trim_start_matches('@'));
+            spans.push(span(found, Pandoc::Citation(Vec::from_iter(keys))));
+        }
+        for found in reference.captures_iter(prose).filter_map(|c| c.get(1)) {
+            spans.push(span(found, Pandoc::Reference(&found.as_str()[1..])));
+        }
+    }
+
+    // references within citations or captions are part of those
+    spans.sort_by_key(|span| span.byte_range.start);
+    let mut end = 0;
+    spans.retain(|span| {
+        let disjoint = span.byte_range.start >= end;
+        if disjoint {
+            end = span.byte_range.end;
+        }
+        disjoint
+    });
+    spans
+}
+
+/// The `{#..}` attributes at the beginning of `s`, after spaces on the same line
+pub(super) fn trailing_attributes(s: &str) -> Option<&str> {
+    static ATTRIBUTES: OnceLock<Regex> = OnceLock::new();
+    let attributes = ATTRIBUTES.get_or_init(|| Regex::new(r"^[ \t]*\{#[^}\n]*\}").unwrap());
+    attributes.find(s).map(|found| found.as_str())
+}
+
+/// Kind and name of a reference of the native syntax for a key of the Pandoc syntax
+fn reference_of(key: &str) -> (&str, &str) {
+    if let Some(name) = key.strip_prefix("eq:") {
+        ("equ", name)
+    } else if let Some(name) = key.strip_prefix("fig:") {
+        ("fig", name)
+    } else {
+        ("bib", key)
+    }
+}
+
+/// Register the labels and citations of a Pandoc construct, see [`collect_references`]
+pub(super) fn collect_pandoc(
+    ctx: &mut BookContext<'_>,
+    span: &PandocSpan<'_>,
+    source: &str,
+    chapter: &ChapterInfo,
+) {
+    match span.pandoc {
+        Pandoc::Figure { attributes, .. } => {
+            // malformed attributes are reported when resolving
+            let Some(refer) = BlockHeader::attributes(attributes, span.lico)
+                .ok()
+                .and_then(|header| header.refer)
+            else {
+                return;
+            };
+            let number = ctx
+                .counters
+                .next(LabelKind::Figure, chapter, &ctx.config.numbering);
+            let label = Label {
+                kind: LabelKind::Figure,
+                number,
+                chapter: chapter.path.clone(),
+            };
+            let location = Location::new(chapter.path.as_deref(), source, span.lico);
+            if let Err(err) = ctx.references.insert(refer, label, location) {
+                ctx.errors.push(err);
+            }
+        }
+        Pandoc::Citation(ref keys) => {
+            for key in keys {
+                if let ("bib", key) = reference_of(key) {
+                    // unknown keys are reported when resolving
+                    let _ = ctx.citations.cite(key);
+                }
+            }
+        }
+        Pandoc::Reference(_) => {}
+    }
+}
+
+/// Resolve a Pandoc construct onto the formatters of the native syntax
+pub(super) fn transform_pandoc(
+    ctx: &mut BookContext<'_>,
+    span: &PandocSpan<'_>,
+    chapter: &ChapterInfo,
+) -> Result<String> {
+    let lico = span.lico;
+    match span.pandoc {
+        Pandoc::Figure {
+            ref caption,
+            ref url,
+            image,
+            attributes,
+        } => {
+            let header = BlockHeader::attributes(attributes, lico)?;
+            let label = header.refer.and_then(|refer| {
+                ctx.references
+                    .get(LabelKind::Figure, refer)
+                    .map(|label| (refer, label))
+            });
+            Ok(match (label, ctx.renderer) {
+                (Some((refer, label)), SupportedRenderer::Html | SupportedRenderer::Markdown) => {
+                    let img = format!(
+                        r#"<img src="{}" alt="{}">"#,
+                        escape_html(url),
+                        escape_html(caption)
+                    );
+                    format_figure(
+                        "",
+                        &img,
+                        refer,
+                        &label.number,
+                        header.title.unwrap_or(caption),
+                        header.width,
+                        ctx.renderer,
+                    )
+                }
+                // the latex renderers show the image themselves
+                _ => image.to_owned(),
+            })
+        }
+        Pandoc::Reference(key) => {
+            let (kind, name) = reference_of(key);
+            reference(ctx, kind, name, lico, chapter)
+        }
+        Pandoc::Citation(ref keys) => Ok(keys
+            .iter()
+            .map(|key| {
+                let (kind, name) = reference_of(key);
+                reference(ctx, kind, name, lico, chapter)
+            })
+            .collect::<Result<Vec<_>>>()?
+            .join(", ")),
+    }
+}
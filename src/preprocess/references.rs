@@ -0,0 +1,133 @@
+use super::*;
+use std::collections::hash_map::Entry;
+
+/// Kind of a labelled block
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LabelKind {
+    Figure,
+    Equation,
+}
+
+/// A labelled block, the target of `$ref:fig:<name>$` or `$ref:equ:<name>$`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Label {
+    pub kind: LabelKind,
+    /// Number as displayed, i.e. `1.2`
+    pub number: String,
+    /// Source path of the defining chapter, relative to the `src` directory
+    pub chapter: Option<PathBuf>,
+}
+
+impl Label {
+    /// Text of a reference to this label
+    pub fn text(&self) -> String {
+        match self.kind {
+            LabelKind::Figure => format!("Figure {}", self.number),
+            LabelKind::Equation => self.number.clone(),
+        }
+    }
+
+    /// Link target of this label, relative to the chapter the reference is located in
+    pub fn href(&self, name: &str, from: &ChapterInfo) -> String {
+        match self.chapter {
+            Some(ref chapter) if from.path.as_ref() != Some(chapter) => {
+                format!("{}#{}", relative_url(from.path.as_deref(), chapter), name)
+            }
+            _ => format!("#{}", name),
+        }
+    }
+}
+
+/// Url of the rendered chapter `to`, relative to the rendered chapter `from`.
+///
+/// Both are source paths relative to the `src` directory, as stored in a chapter.
+pub fn relative_url(from: Option<&Path>, to: &Path) -> String {
+    let names = |path: &Path| {
+        Vec::from_iter(path.components().filter_map(|component| match component {
+            std::path::Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+            _ => None,
+        }))
+    };
+    let from_dir = from.and_then(Path::parent).map(names).unwrap_or_default();
+    let to = names(&to.with_extension("html"));
+
+    let common = from_dir
+        .iter()
+        .zip(to.iter())
+        .take_while(|(a, b)| a == b)
+        .count()
+        // the file name itself is never a common directory
+        .min(to.len().saturating_sub(1));
+
+    let mut url = "../".repeat(from_dir.len() - common);
+    url.push_str(&to[common..].join("/"));
+    url
+}
+
+/// All labels of the book by name, collected before any reference is resolved.
+///
+/// Figures and equations share one namespace, the name is the html id of either.
+#[derive(Debug, Default)]
+pub struct References {
+    labels: HashMap<String, Label>,
+}
+
+impl References {
+    /// Define the label `name` at `location`, a redefinition is an error and keeps the first one
+    pub fn insert(&mut self, name: &str, label: Label, location: Location) -> Result<()> {
+        match self.labels.entry(name.to_owned()) {
+            Entry::Occupied(_) => Err(Error::DuplicateLabel {
+                name: name.to_owned(),
+                location,
+            }),
+            Entry::Vacant(entry) => {
+                entry.insert(label);
+                Ok(())
+            }
+        }
+    }
+
+    /// The label `name` if it is of `kind`
+    pub fn get(&self, kind: LabelKind, name: &str) -> Option<&Label> {
+        self.labels.get(name).filter(|label| label.kind == kind)
+    }
+}
+
+/// Running counters of figures and equations, scoped by the numbering scheme
+#[derive(Debug, Default)]
+pub struct Counters {
+    counts: HashMap<(LabelKind, String), usize>,
+}
+
+impl Counters {
+    /// Next number of a label of `kind` defined in `chapter`, i.e. `3.1.4`
+    pub fn next(
+        &mut self,
+        kind: LabelKind,
+        chapter: &ChapterInfo,
+        numbering: &Numbering,
+    ) -> String {
+        let prefix = match numbering.scheme {
+            NumberingScheme::Global => &[][..],
+            NumberingScheme::Chapter => &chapter.number[..chapter.number.len().min(1)],
+            NumberingScheme::Section => &chapter.number[..],
+        };
+        let scope = match (numbering.scheme, &chapter.path) {
+            // unnumbered chapters are their own scope
+            (NumberingScheme::Chapter | NumberingScheme::Section, Some(path))
+                if chapter.number.is_empty() =>
+            {
+                path.display().to_string()
+            }
+            _ => prefix.iter().join("."),
+        };
+
+        let counter = self.counts.entry((kind, scope)).or_default();
+        *counter += 1;
+        prefix
+            .iter()
+            .map(u32::to_string)
+            .chain(std::iter::once(counter.to_string()))
+            .join(&numbering.separator)
+    }
+}
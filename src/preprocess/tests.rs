@@ -23,7 +23,8 @@ mod dollarsplit {
                     content: $content,
                 }),*
             ];
-            let ist = Vec::from_iter(dollar_split_tags_iter(LIT));
+            let ist = Vec::from_iter(dollar_split_tags_iter(LIT, &[], &mut Vec::new()));
+            assert_eq!(ist.len(), soll.len());
             ist.iter().zip(soll.iter()).enumerate().for_each(|(idx, (ist, soll))| {
                 // assert!(lico > previous_lico);
                 dbg!((&idx, &ist, &soll));
@@ -53,11 +54,46 @@ mod dollarsplit {
     );
 
     test_case!(oneline:
-    r###"a $b$ c"### => (0,2, "$"), (0,4, "$")
+    r###"a $b$ c"### => (1,3, "$"), (1,5, "$")
     );
 
     test_case!(oneline_unclosed:
-        r###"a $b c"### => (0,2,"$"), (0,7,"")
+        r###"a $b c"###
+    );
+
+    test_case!(unclosed_before_closed:
+        "a $b\n$c$" => (2,1,"$"), (2,3,"$")
+    );
+
+    test_case!(escaped:
+        r###"a \$b$ c $d\$e$ \\$f$"### => (1,10,"$"), (1,15,"$"), (1,19,"$"), (1,21,"$")
+    );
+
+    test_case!(currency:
+        "costs $5 and $10.\nOr $ 3.\nAnd $x$2."
+    );
+
+    #[test]
+    fn currency_not_unmatched() {
+        let mut unmatched = Vec::new();
+        let _ = Vec::from_iter(dollar_split_tags_iter(
+            "costs $5 and $10.\nOr $ 3.\npay $5 or more $x$",
+            &[],
+            &mut unmatched,
+        ));
+        assert!(unmatched.is_empty(), "{:?}", unmatched);
+
+        let _ = Vec::from_iter(dollar_split_tags_iter("a $b c\n$2x", &[], &mut unmatched));
+        assert_eq!(unmatched.len(), 1, "{:?}", unmatched);
+        assert_eq!(unmatched[0].lico, LiCo { lineno: 1, column: 3 });
+    }
+
+    test_case!(closing_after_whitespace:
+        "pay $5 or more $x$" => (1,16,"$"), (1,18,"$")
+    );
+
+    test_case!(closing_after_whitespace_unclosed:
+        "a $x $ b"
     );
 
     test_case!(dollar_block_1:
@@ -65,7 +101,7 @@ mod dollarsplit {
 $$
 \epsilon
 $$
-"### => (1,1, "$$"), (3,1, "$$"));
+"### => (2,1, "$$"), (4,1, "$$"));
 
     test_case!(pre_block_w_unclosed_inlines:
 r###"
@@ -74,7 +110,7 @@ $a
 \epsilon
 </pre>
 $4
-"### => (1,0, "$"), (1,3, ""), (5,0,"$"), (5,2, ""));
+"###);
 
     test_case!(all_in_code_block:
 r###"
@@ -85,8 +121,39 @@ $ foo $ $$ $?
     );
 
     test_case!(
-        iter_over_empty_intra_line_sequences: "foo $$_$$ bar" => (0,4,"$"),(0,5,"$"),(0,7,"$"),(0,8,"$")
+        iter_over_empty_intra_line_sequences: "foo $$_$$ bar" => (1,5,"$"),(1,6,"$"),(1,8,"$"),(1,9,"$")
+    );
+
+    test_case!(tilde_fence:
+r###"~~~
+$ foo $
+~~~
+$b$"### => (4,1,"$"), (4,3,"$"));
+
+    test_case!(indented_code_block:
+r###"    $ foo $
+
+$b$"### => (3,1,"$"), (3,3,"$"));
+
+    test_case!(
+        links_and_inline_html: r###"[$x$](http://a.b/$c) <span title="$d">$e$</span>"### => (1,2,"$"),(1,4,"$"),(1,39,"$"),(1,41,"$")
     );
+
+    test_case!(blockquote_and_table:
+r###"> $a$
+
+| $b$ | c |
+|-----|---|
+| `$` | $d$ |"### => (1,3,"$"), (1,5,"$"), (3,3,"$"), (3,5,"$"), (5,9,"$"), (5,11,"$"));
+
+    test_case!(html_block:
+r###"<div>
+$a$
+</div>
+
+$$
+b
+$$"### => (5,1,"$$"), (7,1,"$$"));
 }
 
 mod sequester {
@@ -113,9 +180,10 @@ mod sequester {
                     content: $content,
                 }),*
             ];
-            let split_points_iter = dollar_split_tags_iter(LIT);
+            let split_points_iter = dollar_split_tags_iter(LIT, &[], &mut Vec::new());
             let ist = iter_over_dollar_encompassed_blocks(LIT, split_points_iter);
             let ist = Vec::<Tagged<'_>>::from_iter(ist);
+            assert_eq!(ist.len(), soll.len());
             ist.iter().zip(soll.iter()).enumerate().for_each(|(_idx, (ist, soll)): (usize, (_, &SollSequester))| {
                 assert_eq!(&LIT[soll.bytes.clone()], soll.content, "Test case integrity violated");
                 match dbg!(&ist) {
@@ -173,4 +241,1010 @@ $$"# =>
 1
 $$")
     );
+
+    test_sequester!(keeps_tail:
+    "a $1$ b" =>
+    (K, 0..2, "a "),
+    (R, 2..5, "$1$"),
+    (K, 5..7, " b"));
+
+    test_sequester!(block_in_list_item:
+    "- $$\n  a\n  $$\n2. $$b$$" =>
+    (K, 0..2, "- "),
+    (R, 2..13, "$$\n  a\n  $$"),
+    (K, 13..17, "\n2. "),
+    (R, 17..22, "$$b$$"));
+
+    test_sequester!(keeps_all_without_math:
+    "`$a$` b" =>
+    (K, 0..7, "`$a$` b"));
+
+    #[test]
+    fn block_in_containers() {
+        for (source, container, body) in [
+            ("> Quote\n> $$\n> a +\n>   b\n> $$\n", "> ", "\na +\n  b\n"),
+            ("> > $$\n> > a\n> b\n> > $$", "> > ", "\na\nb\n"),
+            ("- item\n\n  $$\n  a\n    b\n  $$\n", "  ", "\na\n  b\n"),
+            ("> $$equation, e\n> a\n> $$", "> ", "a\n"),
+            ("- $$\n  a\n    b\n  $$\n", "- ", "\na\n  b\n"),
+            ("1. x\n2. $$\n   a\n   $$\n", "2. ", "\na\n"),
+            ("> * $$\n>   a\n>   $$\n", "> * ", "\na\n"),
+        ] {
+            let tags = dollar_split_tags_iter(source, &[], &mut Vec::new());
+            let blocks = Vec::from_iter(iter_over_dollar_encompassed_blocks(source, tags).filter_map(
+                |tagged| match tagged {
+                    Tagged::Replace(content) => Some(content),
+                    Tagged::Keep(_) => None,
+                },
+            ));
+            assert_matches!(&blocks[..], [block] => {
+                assert_eq!(block.container, container);
+                let (_, content) = block_header(&strip_delimiters(block), None).unwrap();
+                assert_eq!(content.math(), body, "{}", source);
+            });
+        }
+    }
+
+    #[test]
+    fn positions() {
+        const LIT: &str = "a $b$\n$$\nc\n$$\n";
+        let tags = dollar_split_tags_iter(LIT, &[], &mut Vec::new());
+        let replaced = Vec::from_iter(iter_over_dollar_encompassed_blocks(LIT, tags).filter_map(
+            |tagged| match tagged {
+                Tagged::Replace(content) => {
+                    Some((content.start, content.end, content.byte_range))
+                }
+                Tagged::Keep(_) => None,
+            },
+        ));
+        // start and end are the positions of the opening and the closing delimiter, the
+        // byte range includes both
+        let lico = |lineno, column| LiCo { lineno, column };
+        assert_eq!(
+            replaced,
+            [
+                (lico(1, 3), lico(1, 5), 2..5),
+                (lico(2, 1), lico(4, 1), 6..13),
+            ]
+        );
+    }
+}
+
+mod references {
+    use super::*;
+
+    fn with_ctx(f: impl FnOnce(&mut BookContext<'_>)) {
+        with_config(ScientificConfig::default(), f)
+    }
+
+    fn with_config(config: ScientificConfig, f: impl FnOnce(&mut BookContext<'_>)) {
+        let fragment_path = std::env::temp_dir().join("mdbook-scientific-references");
+        let mut ctx = BookContext {
+            config: &config,
+            cache: Cache::open(&fragment_path),
+            template: fragments::Template::default(),
+            fragment_path,
+            renderer: SupportedRenderer::Html,
+            references: References::default(),
+            counters: Counters::default(),
+            citations: Citations::default(),
+            used_fragments: Vec::new(),
+            embedded: 0,
+            client_math: 0,
+            errors: Vec::new(),
+            failed: HashMap::new(),
+        };
+        f(&mut ctx)
+    }
+
+    /// Errors of replacing the blocks of `source`, instead of the output
+    fn errors(ctx: &mut BookContext<'_>, source: &str, chapter: &ChapterInfo) -> Vec<Error> {
+        replace_blocks(ctx, source, chapter).unwrap();
+        std::mem::take(&mut ctx.errors)
+    }
+
+    fn chapter(number: &[u32], path: &str) -> ChapterInfo {
+        ChapterInfo {
+            number: number.to_vec(),
+            path: Some(PathBuf::from(path)),
+            preambles: Vec::new(),
+        }
+    }
+
+    const FIRST: &str = r###"See $ref:fig:decay$ and $ref:equ:energy$.
+
+$$ref:equ:energy
+E = mc^2
+$$
+"###;
+
+    const SECOND: &str = r###"$$gnuplot {#fig:decay caption=Decay}
+plot exp(-x)
+$$
+
+$$latex, other, Other
+\draw (0,0) -- (1,1);
+$$
+"###;
+
+    #[test]
+    fn header() {
+        assert_eq!(
+            BlockHeader::parse("ref:gnuplot:decay:Decay: fast\nplot x\n", LiCo::default()).unwrap(),
+            (
+                BlockHeader {
+                    kind: BlockKind::Gnuplot,
+                    refer: Some("decay"),
+                    title: Some("Decay: fast"),
+                    width: None,
+                    preambles: vec![],
+                },
+                "plot x\n"
+            )
+        );
+        assert_matches!(
+            BlockHeader::parse("\nx^2\n", LiCo::default()),
+            Ok((BlockHeader { kind: BlockKind::Equation, refer: None, title: None, .. }, "\nx^2\n"))
+        );
+        assert_matches!(
+            BlockHeader::parse(" {a+b}\n", LiCo::default()),
+            Ok((BlockHeader { refer: None, .. }, " {a+b}\n"))
+        );
+    }
+
+    #[test]
+    fn header_comma_form() {
+        assert_matches!(
+            BlockHeader::parse("equation, energy\nE\n", LiCo::default()),
+            Ok((BlockHeader { kind: BlockKind::Equation, refer: Some("energy"), title: None, .. }, "E\n"))
+        );
+        let source = "gnuplot, decay, \"Decay, fast: x=1\", preamble = 'a b'\nplot x\n";
+        assert_matches!(
+            BlockHeader::parse(source, LiCo::default()),
+            Ok((BlockHeader { title: Some("Decay, fast: x=1"), preambles, .. }, "plot x\n")) => {
+                assert_eq!(preambles, ["a b"]);
+            }
+        );
+        assert_matches!(
+            BlockHeader::parse("latex, , Untitled\nx\n", LiCo::default()),
+            Ok((BlockHeader { kind: BlockKind::Latex, refer: None, title: Some("Untitled"), .. }, "x\n"))
+        );
+    }
+
+    #[test]
+    fn header_attribute_form() {
+        let source = "gnuplot {#fig:decay caption=\"Decay: fast\" width=80%}\nplot x\n";
+        assert_eq!(
+            BlockHeader::parse(source, LiCo::default()).unwrap(),
+            (
+                BlockHeader {
+                    kind: BlockKind::Gnuplot,
+                    refer: Some("decay"),
+                    title: Some("Decay: fast"),
+                    width: Some("80%"),
+                    preambles: vec![],
+                },
+                "plot x\n"
+            )
+        );
+        assert_matches!(
+            BlockHeader::parse(" {#eq:energy preamble=physics}\nE\n", LiCo::default()),
+            Ok((BlockHeader { kind: BlockKind::Equation, refer: Some("energy"), preambles, .. }, "E\n")) => {
+                assert_eq!(preambles, ["physics"]);
+            }
+        );
+        assert_matches!(
+            BlockHeader::parse("latex, cd {caption='Diagram {x}'}\nx\n", LiCo::default()),
+            Ok((BlockHeader { kind: BlockKind::Latex, refer: Some("cd"), title: Some("Diagram {x}"), .. }, "x\n"))
+        );
+    }
+
+    #[test]
+    fn header_legacy_equation() {
+        for source in ["ref:equ:energy\nE\n", "ref:energy\nE\n"] {
+            assert_matches!(
+                BlockHeader::parse(source, LiCo::default()),
+                Ok((BlockHeader { kind: BlockKind::Equation, refer: Some("energy"), .. }, "E\n")),
+                "{}",
+                source
+            );
+        }
+        // the kind alone is an unnamed equation
+        for source in ["ref:equation\nE\n", "ref:equ\nE\n"] {
+            assert_matches!(
+                BlockHeader::parse(source, LiCo::default()),
+                Ok((BlockHeader { kind: BlockKind::Equation, refer: None, .. }, "E\n")),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn header_attributes() {
+        assert_eq!(
+            BlockHeader::parse("ref:equ:energy, preamble=physics\nE\n", LiCo::default())
+                .unwrap()
+                .0,
+            BlockHeader {
+                kind: BlockKind::Equation,
+                refer: Some("energy"),
+                title: None,
+                width: None,
+                preambles: vec!["physics"],
+            }
+        );
+        assert_matches!(
+            BlockHeader::parse("ref:latex:cd:Diagram, commutative, preamble = tikz\nx\n", LiCo::default()),
+            Ok((BlockHeader { title: Some("Diagram, commutative"), preambles, .. }, "x\n")) => {
+                assert_eq!(preambles, ["tikz"]);
+            }
+        );
+        assert_matches!(
+            BlockHeader::parse("preamble=physics, preamble=chem\nx\n", LiCo::default()),
+            Ok((BlockHeader { refer: None, preambles, .. }, "x\n")) => {
+                assert_eq!(preambles, ["physics", "chem"]);
+            }
+        );
+    }
+
+    #[test]
+    fn header_errors() {
+        for source in [
+            "gnuplot, decay, Decay, fast\nplot x\n",
+            "gnuplot, decay, E=mc^2\nplot x\n",
+            "gnuplot, decay, \"Decay\nplot x\n",
+            "gnuplot {#fig:decay caption=Decay\nplot x\n",
+            "gnuplot {#fig:decay .wide}\nplot x\n",
+            "gnuplot, decay {#fig:other}\nplot x\n",
+            "plot, decay, preamble=physics\nplot x\n",
+            "ref:gnuplot:decay\nplot x\n",
+        ] {
+            let lico = LiCo {
+                lineno: 7,
+                column: 3,
+            };
+            assert_matches!(
+                BlockHeader::parse(source, lico),
+                Err(Error::InvalidBlockHeader { location, .. }) if location.lico == lico,
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn front_matter_preambles() {
+        let source = "---\ntitle: Physics\npreamble: [physics, chem]\n---\n# Hi $x$\n";
+        let (len, preambles) = front_matter(source).unwrap();
+        assert_eq!(&source[len..], "# Hi $x$\n");
+        assert_eq!(
+            preambles,
+            [
+                (
+                    "physics".to_owned(),
+                    LiCo {
+                        lineno: 3,
+                        column: 12
+                    }
+                ),
+                (
+                    "chem".to_owned(),
+                    LiCo {
+                        lineno: 3,
+                        column: 21
+                    }
+                )
+            ]
+        );
+        assert_eq!(blank_front_matter(source), "\n\n\n\n# Hi $x$\n");
+        assert_eq!(front_matter("---\nnever closed\n"), None);
+        assert_eq!(front_matter("# Hi\n---\n"), None);
+
+        // a thematic break followed by prose is kept
+        let source = "---\nSome prose: with a colon.\n\nMore prose\n---\n";
+        assert_eq!(front_matter(source), None);
+        assert_eq!(blank_front_matter(source), source);
+        assert_eq!(front_matter("---\ntitle: Physics\n---\n"), None);
+        assert_eq!(front_matter("---\npreamble: physics\n\n---\n").unwrap().0, 27);
+    }
+
+    #[test]
+    fn preamble_profiles() {
+        let mut config = ScientificConfig::default();
+        config.preambles.insert(
+            "physics".to_owned(),
+            PreambleProfile {
+                packages: vec!["physics".to_owned()],
+                ..Default::default()
+            },
+        );
+        config.preambles.insert(
+            "chem".to_owned(),
+            PreambleProfile {
+                packages: vec!["chemfig".to_owned()],
+                ..Default::default()
+            },
+        );
+        let chapter = ChapterInfo {
+            preambles: vec![("chem".to_owned(), LiCo::default())],
+            ..Default::default()
+        };
+
+        let mut fragments = Vec::new();
+        collect_fragments(
+            &config,
+            "$a$\n$$preamble=physics\nb\n$$\n$$ref:latex:c:C, preamble=physics\nc\n$$",
+            &chapter,
+            &mut fragments,
+        );
+        let preambles = Vec::from_iter(fragments.iter().map(|fragment| fragment.preamble.as_str()));
+        assert_eq!(
+            preambles,
+            [
+                "\\usepackage{chemfig}\n",
+                "\\usepackage{chemfig}\n\\usepackage{physics}\n",
+                "",
+            ]
+        );
+
+        // reported when replacing
+        let source = "x\n\n$$preamble=nope\nb\n$$";
+        collect_fragments(&config, source, &chapter, &mut fragments);
+        assert_eq!(fragments.len(), 3);
+        with_config(config, |ctx| {
+            assert_matches!(
+                &errors(ctx, source, &chapter)[..],
+                [Error::UnknownPreamble { name, location }] => {
+                    assert_eq!(name, "nope");
+                    assert_eq!(
+                        location.lico,
+                        LiCo {
+                            lineno: 3,
+                            column: 3
+                        }
+                    );
+                    assert_eq!(location.line, "$$preamble=nope");
+                }
+            );
+        });
+    }
+
+    #[test]
+    fn collect_all_chapters_first() {
+        with_ctx(|ctx| {
+            collect_references(ctx, FIRST, &chapter(&[1], "first.md"));
+            collect_references(ctx, SECOND, &chapter(&[2], "second.md"));
+
+            let get = |kind, name| ctx.references.get(kind, name).unwrap();
+            assert_eq!(get(LabelKind::Equation, "energy").number, "1.1");
+            assert_eq!(get(LabelKind::Figure, "decay").number, "2.1");
+            assert_eq!(get(LabelKind::Figure, "other").text(), "Figure 2.2");
+        });
+    }
+
+    #[test]
+    fn numbering_schemes() {
+        let numbers = |scheme, separator: &str| {
+            let config = ScientificConfig {
+                numbering: Numbering {
+                    scheme,
+                    separator: separator.to_owned(),
+                },
+                ..Default::default()
+            };
+            let mut numbers = Vec::new();
+            with_config(config, |ctx| {
+                collect_references(ctx, SECOND, &chapter(&[3, 1], "a.md"));
+                collect_references(
+                    ctx,
+                    "$$ref:latex:third:Third\n$$\n",
+                    &chapter(&[3, 2], "b.md"),
+                );
+                collect_references(ctx, FIRST, &chapter(&[4], "c.md"));
+                let unnumbered = SECOND.replace("decay", "x").replace("other", "y");
+                collect_references(ctx, &unnumbered, &chapter(&[], "d.md"));
+                for (kind, name) in [
+                    (LabelKind::Figure, "decay"),
+                    (LabelKind::Figure, "other"),
+                    (LabelKind::Figure, "third"),
+                    (LabelKind::Equation, "energy"),
+                    (LabelKind::Figure, "x"),
+                    (LabelKind::Figure, "y"),
+                ] {
+                    numbers.push(ctx.references.get(kind, name).unwrap().number.clone());
+                }
+            });
+            numbers
+        };
+
+        assert_eq!(
+            numbers(NumberingScheme::Section, "."),
+            ["3.1.1", "3.1.2", "3.2.1", "4.1", "1", "2"]
+        );
+        assert_eq!(
+            numbers(NumberingScheme::Chapter, "-"),
+            ["3-1", "3-2", "3-3", "4-1", "1", "2"]
+        );
+        assert_eq!(
+            numbers(NumberingScheme::Global, "."),
+            ["1", "2", "3", "1", "4", "5"]
+        );
+    }
+
+    #[test]
+    fn collect_and_skip_cached_fragments() {
+        let config = ScientificConfig::default();
+        let mut fragments = Vec::new();
+        let chapter = ChapterInfo::default();
+        collect_fragments(&config, FIRST, &chapter, &mut fragments);
+        collect_fragments(&config, SECOND, &chapter, &mut fragments);
+        collect_fragments(&config, "Again $x$ and $x$.", &chapter, &mut fragments);
+
+        let kinds = Vec::from_iter(
+            fragments
+                .iter()
+                .map(|fragment| (fragment.kind, fragment.content.s, fragment.zoom)),
+        );
+        assert_eq!(
+            kinds,
+            [
+                (BlockKind::Equation, "E = mc^2\n", config.block_zoom),
+                (BlockKind::Gnuplot, "plot exp(-x)\n", config.figure_zoom),
+                (BlockKind::Latex, "\\draw (0,0) -- (1,1);\n", config.figure_zoom),
+                (BlockKind::Equation, "x", config.inline_zoom),
+                (BlockKind::Equation, "x", config.inline_zoom),
+            ]
+        );
+
+        // everything is cached, so nothing is spawned
+        with_ctx(|ctx| {
+            fs::create_dir_all(&ctx.fragment_path).unwrap();
+            for fragment in &fragments {
+                let name = fragment.name(ctx);
+                let svg = ctx.fragment_path.join(&name).with_extension("svg");
+                fs::write(svg, "<svg/>").unwrap();
+                ctx.cache.record(&name, None).unwrap();
+            }
+            render_fragments(ctx, fragments);
+            assert!(ctx.failed.is_empty());
+        });
+    }
+
+    #[test]
+    fn report_failed_fragments() {
+        let config = ScientificConfig::default();
+        let source = "$\\oops$ and $\\oops$";
+        let mut fragments = Vec::new();
+        collect_fragments(&config, source, &ChapterInfo::default(), &mut fragments);
+
+        with_config(config.clone(), |ctx| {
+            let name = fragments[0].name(ctx);
+            let err = Error::InvalidMath {
+                message: "Undefined control sequence.".to_owned(),
+                context: String::new(),
+                location: fragments[0].origin.clone(),
+            };
+            ctx.failed.insert(name, Arc::new(err));
+
+            // the stored error is reported for every use, without rendering again
+            let errors = errors(ctx, source, &ChapterInfo::default());
+            assert_matches!(&errors[..], [Error::Shared(a), Error::Shared(b)] => {
+                assert!(Arc::ptr_eq(a, b));
+            });
+        });
+    }
+
+    #[test]
+    fn mathml_backend() {
+        let config = ScientificConfig {
+            math_backend: MathBackend::Mathml,
+            ..Default::default()
+        };
+        let source = "Inline $x^2$ and $\\tikz$.\n\n$$ref:equ:sum\n\\sum_i i\n$$\n";
+        let chapter = ChapterInfo::default();
+
+        // only the unsupported equation is rendered to svg
+        let mut fragments = Vec::new();
+        collect_fragments(&config, source, &chapter, &mut fragments);
+        assert_eq!(
+            Vec::from_iter(fragments.iter().map(|fragment| fragment.content.s)),
+            ["\\tikz"]
+        );
+
+        with_config(config, |ctx| {
+            // no `latex` is needed without the unsupported one
+            let source = source.replace(" and $\\tikz$", " and");
+            collect_references(ctx, &source, &chapter);
+            let replaced = replace_blocks(ctx, &source, &chapter).unwrap();
+            assert!(replaced.starts_with(
+                r#"Inline <math xmlns="http://www.w3.org/1998/Math/MathML"><msup><mi>x</mi><mn>2</mn></msup></math> and."#
+            ));
+            assert!(replaced.contains(r#"<div id="sum" class="equation">"#));
+            assert!(replaced.contains(
+                r#"<math xmlns="http://www.w3.org/1998/Math/MathML" display="block"><munder>"#
+            ));
+            assert!(ctx.used_fragments.is_empty());
+        });
+    }
+
+    #[test]
+    fn client_backend() {
+        let config = ScientificConfig {
+            math_backend: MathBackend::Client,
+            client_script: Some("katex/render.js".to_owned()),
+            ..Default::default()
+        };
+        let mut fragments = Vec::new();
+        collect_fragments(&config, FIRST, &ChapterInfo::default(), &mut fragments);
+        assert!(fragments.is_empty());
+
+        with_config(config, |ctx| {
+            let nested = chapter(&[1, 1], "part/nested.md");
+            let replaced = replace_blocks(ctx, "$a_*b*_$\n", &nested).unwrap();
+            assert_eq!(
+                replaced,
+                r#"<span class="math inline">&#x5C;(a&#x5F;&#x2A;b&#x2A;&#x5F;&#x5C;)</span>
+
+<script src="../katex/render.js"></script>
+"#
+            );
+            // only chapters with math include the script
+            let replaced = replace_blocks(ctx, "No math.\n", &nested).unwrap();
+            assert_eq!(replaced, "No math.\n");
+
+            // and only for the html renderer
+            for renderer in [SupportedRenderer::Markdown, SupportedRenderer::Latex] {
+                ctx.renderer = renderer;
+                let replaced = replace_blocks(ctx, "$a$\n", &nested).unwrap();
+                assert!(!replaced.contains("<script"), "{}", replaced);
+            }
+        });
+    }
+
+    #[test]
+    fn pandoc_syntax() {
+        const PANDOC: &str = r###"See @eq:energy, @fig:decay and [@einstein; @fig:decay], not a@eq:energy.
+
+$$
+E = mc^2
+$$ {#eq:energy}
+
+![Decay *fast*](decay.svg){#fig:decay width=50%}
+
+`@eq:energy` stays.
+"###;
+        let einstein = crate::bibliography::BibEntry {
+            key: "einstein".to_owned(),
+            kind: "article".to_owned(),
+            authors: Vec::new(),
+            title: None,
+            venue: None,
+            year: None,
+            doi: None,
+            url: None,
+        };
+        let config = ScientificConfig {
+            syntax: Syntax::Pandoc,
+            math_backend: MathBackend::Client,
+            ..Default::default()
+        };
+        with_config(config, |ctx| {
+            ctx.citations = Citations::new(CitationStyle::Numeric, vec![einstein], &[]);
+            let first = chapter(&[1], "first.md");
+            collect_references(ctx, PANDOC, &first);
+            let get = |kind, name| ctx.references.get(kind, name).unwrap();
+            assert_eq!(get(LabelKind::Equation, "energy").text(), "1.1");
+            assert_eq!(get(LabelKind::Figure, "decay").text(), "Figure 1.1");
+
+            let replaced = replace_blocks(ctx, PANDOC, &first).unwrap();
+            for expected in [
+                r#"See <a class="equ_ref" href='#energy'>Eq. (1.1)</a>, <a class="fig_ref" href='#decay'>Figure 1.1</a> and <a class="bib_ref""#,
+                r#"#einstein'>[1]</a>, <a class="fig_ref" href='#decay'>Figure 1.1</a>, not a@eq:energy."#,
+                r#"<div id="energy" class="equation">"#,
+                r#"<figure id="decay" class="figure" style="width: 50%">"#,
+                r#"<img src="decay.svg" alt="Decay fast">"#,
+                "<figcaption>Figure 1.1 Decay fast</figcaption>",
+                "`@eq:energy` stays.",
+            ] {
+                assert!(replaced.contains(expected), "{}\n{}", expected, replaced);
+            }
+            assert!(!replaced.contains("{#"), "{}", replaced);
+        });
+
+        // the native syntax keeps all of it
+        let config = ScientificConfig {
+            math_backend: MathBackend::Client,
+            ..Default::default()
+        };
+        with_config(config, |ctx| {
+            let first = chapter(&[1], "first.md");
+            collect_references(ctx, PANDOC, &first);
+            assert!(ctx.references.get(LabelKind::Equation, "energy").is_none());
+            let replaced = replace_blocks(ctx, PANDOC, &first).unwrap();
+            assert!(replaced.starts_with("See @eq:energy, @fig:decay and [@einstein; @fig:decay]"));
+            assert!(replaced.contains("{#eq:energy}"));
+        });
+    }
+
+    #[test]
+    fn pandoc_attribute_errors() {
+        let config = ScientificConfig {
+            syntax: Syntax::Pandoc,
+            math_backend: MathBackend::Client,
+            ..Default::default()
+        };
+        with_config(config, |ctx| {
+            let first = chapter(&[1], "first.md");
+            assert_matches!(
+                &errors(ctx, "\n$$\nx\n$$ {#eq:a caption}\n", &first)[..],
+                [Error::InvalidBlockHeader { location, .. }] if location.lico.lineno == 4
+            );
+            assert_matches!(
+                &errors(ctx, "See @fig:missing.\n", &first)[..],
+                [Error::InvalidReference { .. }]
+            );
+        });
+    }
+
+    #[test]
+    fn unknown_pandoc_reference_keeps_prose() {
+        let config = ScientificConfig {
+            syntax: Syntax::Pandoc,
+            math_backend: MathBackend::Client,
+            ..Default::default()
+        };
+        with_config(config, |ctx| {
+            let first = chapter(&[1], "first.md");
+            let source = "$a$ before @fig:missing after, see @fig:d.\n\n![Decay](d.svg){#fig:d}\n";
+            collect_references(ctx, source, &first);
+            let replaced = replace_blocks(ctx, source, &first).unwrap();
+            assert!(
+                replaced.contains(" before <span class=\"scientific_error\""),
+                "{}",
+                replaced
+            );
+            assert!(
+                replaced
+                    .contains(r#"</span> after, see <a class="fig_ref" href='#d'>Figure 1.1</a>."#),
+                "{}",
+                replaced
+            );
+            assert!(replaced.contains(r#"<figure id="d""#), "{}", replaced);
+            assert_matches!(&ctx.errors[..], [Error::InvalidReference { to, .. }] if to == "missing");
+        });
+    }
+
+    #[test]
+    fn math_in_figure_caption() {
+        let config = ScientificConfig {
+            syntax: Syntax::Pandoc,
+            math_backend: MathBackend::Client,
+            ..Default::default()
+        };
+        with_config(config, |ctx| {
+            let first = chapter(&[1], "first.md");
+            let source = "See @fig:d and $y$.\n\n![Decay $x$](d.svg){#fig:d}\n";
+            collect_references(ctx, source, &first);
+            assert_eq!(ctx.references.get(LabelKind::Figure, "d").unwrap().text(), "Figure 1.1");
+
+            let replaced = replace_blocks(ctx, source, &first).unwrap();
+            assert!(ctx.errors.is_empty(), "{:?}", ctx.errors);
+            assert!(replaced.contains(r#"<figure id="d" class="figure">"#), "{}", replaced);
+            assert!(replaced.contains(r#"<img src="d.svg" alt="Decay $x$">"#), "{}", replaced);
+            assert!(!replaced.contains("](d.svg)"), "{}", replaced);
+            assert!(!replaced.contains("&#x5C;(x&#x5C;)"), "{}", replaced);
+            assert!(replaced.contains("&#x5C;(y&#x5C;)"), "{}", replaced);
+        });
+    }
+
+    #[test]
+    fn located_snippets() {
+        with_ctx(|ctx| {
+            let first = chapter(&[1], "first.md");
+            let errors = errors(ctx, "# Title\nSee $ref:fig:nope$.\n", &first);
+            assert_eq!(
+                errors[0].to_string(),
+                "Invalid reference to `nope`
+ --> first.md:2:6
+  |
+2 | See $ref:fig:nope$.
+  |      ^"
+            );
+
+            replace_blocks(ctx, "\n$$gnuplot, {#fig:a\nplot x\n$$\n", &first).unwrap();
+            assert_eq!(
+                ctx.errors[0].to_string(),
+                "Invalid block header: unterminated `{`, attributes must end the line
+ --> first.md:2:3
+  |
+2 | $$gnuplot, {#fig:a
+  |   ^"
+            );
+        });
+    }
+
+    #[test]
+    fn asset_urls() {
+        let url = |from: Option<&str>, dir: &str| {
+            asset_url(from.map(Path::new), Path::new(dir), Path::new("x.svg"))
+        };
+        assert_eq!(url(None, "assets"), "assets/x.svg");
+        assert_eq!(url(Some("intro.md"), "assets"), "assets/x.svg");
+        assert_eq!(url(Some("part1/ch.md"), "assets"), "../assets/x.svg");
+        assert_eq!(url(Some("a/b/c.md"), "static/math"), "../../static/math/x.svg");
+    }
+
+    #[test]
+    fn escaped_captions() {
+        let figure = format_figure(
+            "",
+            "<svg/>",
+            "a\"b",
+            "1.1",
+            "x < y & \"z\"",
+            None,
+            SupportedRenderer::Html,
+        );
+        assert!(figure.contains(r#"<figure id="a&quot;b" class="figure">"#), "{}", figure);
+        assert!(
+            figure.contains("<figcaption>Figure 1.1 x &lt; y &amp; &quot;z&quot;</figcaption>"),
+            "{}",
+            figure
+        );
+        let equation = format_equation_block("", "<svg/>", "a\"b", "1.1", SupportedRenderer::Html);
+        assert!(equation.contains(r#"<div id="a&quot;b" class="equation">"#), "{}", equation);
+    }
+
+    #[test]
+    fn embed_modes() {
+        const SVG: &str = r#"<?xml version='1.0' encoding='UTF-8'?>
+<!-- This file was generated by dvisvgm 2.13.1 -->
+<svg version='1.1' xmlns='http://www.w3.org/2000/svg' xmlns:xlink='http://www.w3.org/1999/xlink'>
+<style type='text/css'>
+<![CDATA[@font-face{font-family:cmmi10;src:url(data:application/x-font-woff;base64,d09G)}
+text.f0 {font-family:cmmi10;font-size:9.96px}
+]]>
+</style>
+<defs><clipPath id='c0'><path d='M0 0'/></clipPath></defs>
+<g clip-path='url(#c0)'><use xlink:href='#g0'/><use xlink:href='#other'/>
+<text class='f0' x='1.5'>x</text></g>
+</svg>
+"#;
+
+        let mut embedded = Vec::new();
+        for embed in [Embed::Object, Embed::Img, Embed::Inline] {
+            let config = ScientificConfig {
+                embed,
+                ..Default::default()
+            };
+            with_config(config, |ctx| {
+                fs::create_dir_all(&ctx.fragment_path).unwrap();
+                fs::write(ctx.fragment_path.join("embed.svg"), SVG).unwrap();
+                let replacement = Replacement {
+                    content: Content {
+                        s: "a < b",
+                        start: LiCo::default(),
+                        end: LiCo::default(),
+                        byte_range: 0..5,
+                        delimiter: Dollar::Start("$"),
+                        container: "",
+                    },
+                    intermediate: None,
+                    svg: PathBuf::from("embed.svg"),
+                    metrics: None,
+                };
+                let chapter = ChapterInfo::default();
+                embedded.push(
+                    embed_svg(ctx, &chapter, &replacement, Some("equation_inline")).unwrap(),
+                );
+                embedded.push(embed_svg(ctx, &chapter, &replacement, None).unwrap());
+            });
+        }
+
+        assert_eq!(
+            embedded[0],
+            r#"<object class="equation_inline" data="assets/embed.svg" type="image/svg+xml"></object>"#
+        );
+        assert_eq!(
+            embedded[2],
+            r#"<img class="equation_inline" src="assets/embed.svg" alt="a &lt; b">"#
+        );
+        assert_eq!(
+            embedded[4],
+            r#"<svg class="equation_inline" version='1.1' xmlns='http://www.w3.org/2000/svg' xmlns:xlink='http://www.w3.org/1999/xlink'>
+<style type='text/css'>
+<![CDATA[@font-face{font-family:sci1-cmmi10;src:url(data:application/x-font-woff;base64,d09G)}
+text.sci1-f0 {font-family:sci1-cmmi10;font-size:9.96px}
+]]>
+</style>
+<defs><clipPath id='sci1-c0'><path d='M0 0'/></clipPath></defs>
+<g clip-path='url(#sci1-c0)'><use xlink:href='#g0'/><use xlink:href='#other'/>
+<text class='sci1-f0' x='1.5'>x</text></g>
+</svg>"#
+        );
+        // unique within the page
+        assert!(embedded[5].contains("id='sci2-c0'"));
+    }
+
+    #[test]
+    fn baseline_alignment() {
+        with_ctx(|ctx| {
+            let replacement = Replacement {
+                content: Content {
+                    s: "y_0",
+                    start: LiCo::default(),
+                    end: LiCo::default(),
+                    byte_range: 0..3,
+                    delimiter: Dollar::Start("$"),
+                    container: "",
+                },
+                intermediate: None,
+                svg: PathBuf::from("baseline.svg"),
+                metrics: Some(Metrics {
+                    height: 9.,
+                    depth: 3.,
+                }),
+            };
+            let image = embed_svg(
+                ctx,
+                &ChapterInfo::default(),
+                &replacement,
+                Some("equation_inline"),
+            )
+            .unwrap();
+            assert_eq!(
+                image,
+                r#"<object class="equation_inline" style="vertical-align: -0.250em" data="assets/baseline.svg" type="image/svg+xml"></object>"#
+            );
+        });
+    }
+
+    #[test]
+    fn resolve_forward_and_across_chapters() {
+        with_ctx(|ctx| {
+            let first = chapter(&[1], "first.md");
+            collect_references(ctx, FIRST, &first);
+            collect_references(ctx, SECOND, &chapter(&[2], "second.md"));
+
+            let source = "See $ref:fig:decay$ and $ref:equ:energy$.";
+            let replaced = replace_blocks(ctx, source, &first).unwrap();
+            assert_eq!(
+                replaced,
+                r#"See <a class="fig_ref" href='second.html#decay'>Figure 2.1</a> and <a class="equ_ref" href='#energy'>Eq. (1.1)</a>."#
+            );
+        });
+    }
+
+    #[test]
+    fn relative_urls() {
+        let url = |from: &str, to: &str| relative_url(Some(Path::new(from)), Path::new(to));
+        assert_eq!(url("a.md", "b.md"), "b.html");
+        assert_eq!(url("part1/a.md", "part1/b.md"), "b.html");
+        assert_eq!(url("chapter1/a.md", "chapter2/intro.md"), "../chapter2/intro.html");
+        assert_eq!(url("a/b/c.md", "bibliography.md"), "../../bibliography.html");
+        assert_eq!(url("a.md", "x/y/z.md"), "x/y/z.html");
+        assert_eq!(relative_url(None, Path::new("x/y.md")), "x/y.html");
+    }
+
+    #[test]
+    fn resolve_from_nested_chapter() {
+        with_ctx(|ctx| {
+            collect_references(ctx, FIRST, &chapter(&[1], "part1/first.md"));
+            let replaced = replace_blocks(ctx, "$ref:equ:energy$", &chapter(&[2, 1], "part2/sub/second.md")).unwrap();
+            assert_eq!(
+                replaced,
+                r#"<a class="equ_ref" href='../../part1/first.html#energy'>Eq. (1.1)</a>"#
+            );
+        });
+    }
+
+    #[test]
+    fn reference_of_other_kind() {
+        with_ctx(|ctx| {
+            let first = chapter(&[1], "first.md");
+            collect_references(ctx, FIRST, &first);
+            collect_references(ctx, SECOND, &chapter(&[2], "second.md"));
+
+            let errors = errors(ctx, "$ref:equ:decay$ and $ref:fig:energy$", &first);
+            assert_matches!(
+                &errors[..],
+                [Error::InvalidReference { to: a, .. }, Error::InvalidReference { to: b, .. }] => {
+                    assert_eq!((a.as_str(), b.as_str()), ("decay", "energy"));
+                }
+            );
+        });
+    }
+
+    #[test]
+    fn nocite_disambiguates_before_citing() {
+        let entry = |key: &str, title: &str| crate::bibliography::BibEntry {
+            key: key.to_owned(),
+            kind: "article".to_owned(),
+            authors: vec![crate::bibliography::Author {
+                first: "Albert".to_owned(),
+                last: "Einstein".to_owned(),
+            }],
+            title: Some(title.to_owned()),
+            venue: None,
+            year: Some("1905".to_owned()),
+            doi: None,
+            url: None,
+        };
+        with_ctx(|ctx| {
+            ctx.citations = Citations::new(
+                CitationStyle::AuthorYear,
+                vec![entry("relativity", "A"), entry("quanta", "B")],
+                &[],
+            );
+            let first = chapter(&[1], "first.md");
+            let source = "See $ref:bib:relativity$.\n\n$ref:nocite:quanta$\n";
+            collect_references(ctx, source, &first);
+            let replaced = replace_blocks(ctx, source, &first).unwrap();
+            assert!(replaced.contains(">(Einstein, 1905a)</a>"), "{}", replaced);
+            let html = ctx.citations.render_html();
+            assert!(html.contains("(Einstein, 1905a)</a>"), "{}", html);
+            assert!(html.contains("(Einstein, 1905b)</a>"), "{}", html);
+        });
+    }
+
+    #[test]
+    fn same_name_of_both_kinds() {
+        let config = ScientificConfig {
+            syntax: Syntax::Pandoc,
+            math_backend: MathBackend::Client,
+            ..Default::default()
+        };
+        with_config(config, |ctx| {
+            let first = chapter(&[1], "first.md");
+            let source = "$$\nE\n$$ {#eq:decay}\n\n![Decay](decay.svg){#fig:decay}\n";
+            collect_references(ctx, source, &first);
+            assert_matches!(
+                &ctx.errors[..],
+                [Error::DuplicateLabel { name, location }] => {
+                    assert_eq!(name, "decay");
+                    assert_eq!(location.lico.lineno, 5);
+                }
+            );
+            assert_eq!(ctx.references.get(LabelKind::Equation, "decay").unwrap().text(), "1.1");
+            assert!(ctx.references.get(LabelKind::Figure, "decay").is_none());
+
+            let replaced = replace_blocks(ctx, source, &first).unwrap();
+            assert_eq!(replaced.matches(r#"id="decay""#).count(), 1, "{}", replaced);
+        });
+    }
+
+    #[test]
+    fn duplicate_label() {
+        with_ctx(|ctx| {
+            let second = chapter(&[2], "second.md");
+            collect_references(ctx, SECOND, &second);
+            collect_references(ctx, "\n$$latex, decay, Again\nx\n$$\n", &second);
+            assert_matches!(
+                &ctx.errors[..],
+                [Error::DuplicateLabel { name, location }] => {
+                    assert_eq!(name, "decay");
+                    assert_eq!(location.lico, LiCo { lineno: 2, column: 3 });
+                }
+            );
+            assert_eq!(ctx.references.get(LabelKind::Figure, "decay").unwrap().number, "2.1");
+        });
+    }
+
+    #[test]
+    fn unknown_reference() {
+        with_ctx(|ctx| {
+            let replaced =
+                replace_blocks(ctx, "$ref:fig:a$ and $ref:fig:b$", &ChapterInfo::default())
+                    .unwrap();
+            assert!(replaced.starts_with(r#"<span class="scientific_error" title="Invalid"#));
+            assert!(replaced.contains(">Error: Invalid reference to `a`</span> and <span"));
+            assert_matches!(
+                &ctx.errors[..],
+                [Error::InvalidReference { to: a, .. }, Error::InvalidReference { to: b, .. }] => {
+                    assert_eq!((a.as_str(), b.as_str()), ("a", "b"));
+                }
+            );
+        });
+    }
 }
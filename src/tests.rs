@@ -1,4 +1,5 @@
 use super::*;
+use assert_matches::assert_matches;
 
 const TESTCASE: &str = r###"
 
@@ -17,16 +18,358 @@ $$ref:fxblck
 a = sqrt(2)
 $$
 
-As seen in $ref:fxblck$ yada.
+As seen in $ref:equ:fxblck$ yada.
 
 "###;
 
 const OUTPUT_MARKDOWN: &str = r###"
 
+# Hello there
+
+I link <span class="math inline">&#x5C;(f&#x5C;)</span> and I <span class="math inline">&#x5C;(x&#x5C;)</span> but `not`
+so `$nested`. <span class="math inline">&#x5C;(x = y&#x5C;)</span>.
+
+```sh
+$ foo
+bar
+baz
+```
+
+<div id="fxblck" class="equation">
+                    <div class="equation_inner">
+                        <div class="math display">&#x5C;&#x5B;a = sqrt(2)&#x5C;&#x5D;</div>
+                    </div><span>(1.1)</span>
+                </div>
 
+As seen in <a class="equ_ref" href='#fxblck'>Eq. (1.1)</a> yada.
 
 
+<script src="katex.js"></script>
 "###;
 
+/// Run the preprocessor on `chapters` of the html renderer, numbered from `1`
+fn preprocess(
+    extra_config: &str,
+    chapters: &[(&str, &str)],
+) -> std::result::Result<Book, mdbook::errors::Error> {
+    let root = std::env::temp_dir().join("mdbook-scientific-end2end");
+    let fragments = root.join("fragments");
+    let book_toml = format!(
+        r#"
+[book]
+src = "src"
+
+[preprocessor.scientific]
+fragment_path = {:?}
+math_backend = "client"
+client_script = "katex.js"
+{}
+"#,
+        fragments.display().to_string(),
+        extra_config
+    );
+    let config = mdbook::Config::from_str(&book_toml).unwrap();
+
+    let mut book = Book::new();
+    for (number, (path, content)) in (1..).zip(chapters) {
+        let mut chapter = Chapter::new("Hello", content.to_string(), path, Vec::new());
+        chapter.number = Some(mdbook::book::SectionNumber(vec![number]));
+        book.push_item(chapter);
+    }
+
+    // the context can only be created the way `mdbook` hands it to preprocessors
+    let input = serde_json::json!([
+        {
+            "root": root,
+            "config": config,
+            "renderer": "html",
+            "mdbook_version": mdbook::MDBOOK_VERSION,
+        },
+        book,
+    ]);
+    let (ctx, book) =
+        mdbook::preprocess::CmdPreprocessor::parse_input(input.to_string().as_bytes()).unwrap();
+
+    Scientific::new().run(&ctx, book)
+}
+
+#[test]
+fn end2end() {
+    let book = preprocess("", &[("hello.md", TESTCASE)]).unwrap();
+    let Some(BookItem::Chapter(chapter)) = book.iter().next() else {
+        panic!("The chapter is kept. qed");
+    };
+    assert_eq!(chapter.content, OUTPUT_MARKDOWN);
+}
+
+#[test]
+fn unnamed_block_is_displayed() {
+    let book = preprocess("", &[("hello.md", "$$\na = b\n$$\n")]).unwrap();
+    let Some(BookItem::Chapter(chapter)) = book.iter().next() else {
+        panic!("The chapter is kept. qed");
+    };
+    assert!(
+        chapter.content.starts_with(
+            r#"<div class="equation"><div class="equation_inner"><div class="math display">"#
+        ),
+        "{}",
+        chapter.content
+    );
+}
+
+#[test]
+fn errors_of_all_chapters() {
+    let chapters = [
+        ("first.md", "See $ref:fig:nope$.\n"),
+        (
+            "second.md",
+            "\n$$gnuplot, {#fig:a\nplot x\n$$\n$ref:equ:nope$\n",
+        ),
+    ];
+    let err = preprocess("", &chapters).unwrap_err().to_string();
+    assert!(err.starts_with("3 errors occurred:"), "{}", err);
+    assert!(err.contains("--> first.md:1:6"), "{}", err);
+    assert!(err.contains("--> second.md:2:3"), "{}", err);
+    assert!(err.contains("--> second.md:5:2"), "{}", err);
+
+    let book = preprocess("strict = false", &chapters).unwrap();
+    let Some(BookItem::Chapter(chapter)) = book.iter().next() else {
+        panic!("The chapter is kept. qed");
+    };
+    assert!(chapter
+        .content
+        .starts_with(r#"See <span class="scientific_error""#));
+}
+
+/// `bib2xhtml` stand-in listing the keys of the file and its arguments
+#[cfg(unix)]
+const BIB2XHTML: &str = r#"#!/bin/sh
+echo '<dl class="bib2xhtml">'
+echo "<dt>$*</dt>"
+sed -n 's/^@[a-z]*{\([^,]*\),.*/<dt><a name="\1">\1<\/a><\/dt>/p' "$5"
+echo '</dl>'
+"#;
+
+#[test]
+#[cfg(unix)]
+fn bib2xhtml_lists_all_entries_in_file_order() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = std::env::temp_dir().join("mdbook-scientific-bib2xhtml");
+    fs::create_dir_all(&dir).unwrap();
+    let script = dir.join("bib2xhtml.pl");
+    if !script.exists() {
+        fs::write(&script, BIB2XHTML).unwrap();
+        fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+    let bib = dir.join("literature.bib");
+    fs::write(
+        &bib,
+        "@article{uncited,\n  title = {Never},\n  year = {1900}\n}\n\n\
+         @article{einstein,\n  title = {Energy},\n  year = {1905}\n}\n",
+    )
+    .unwrap();
+
+    let book = preprocess(
+        &format!("bibliography = {:?}\nbib2xhtml = {:?}", bib, dir),
+        &[("hello.md", "As in $ref:bib:einstein$.\n")],
+    )
+    .unwrap();
+    let Some(BookItem::Chapter(chapter)) = book.iter().last() else {
+        panic!("The bibliography is the last chapter. qed");
+    };
+    // numbered in file order and uncited entries included, unlike the native rendering
+    let content = &chapter.content;
+    assert!(content.contains("<dt>-s unsort -u -U "), "{}", content);
+    let uncited = content.find(r#"<a id="uncited">"#).expect(content);
+    let einstein = content.find(r#"<a id="einstein">"#).expect(content);
+    assert!(uncited < einstein, "{}", content);
+}
+
+fn config(s: &str) -> Result<ScientificConfig> {
+    let table = toml::from_str::<toml::value::Table>(s).unwrap();
+    ScientificConfig::from_table(&table)
+}
+
+#[test]
+fn config_defaults() {
+    assert_eq!(config("").unwrap(), ScientificConfig::default());
+}
+
+#[test]
+fn config_ignores_unknown_and_mdbook_keys() {
+    let table = toml::from_str::<toml::value::Table>(
+        r#"
+renderers = ["html"]
+fragment_path = "frags"
+bibliography = "literature.bib"
+fragmet_path = "typo"
+"#,
+    )
+    .unwrap();
+    let (cfg, unknown) = ScientificConfig::parse(&table).unwrap();
+    assert_eq!(cfg.fragment_path, PathBuf::from("frags"));
+    assert_eq!(cfg.bibliography, Some(PathBuf::from("literature.bib")));
+    assert_eq!(unknown, ["fragmet_path"]);
+}
+
+#[test]
+fn config_mdbook_keys_are_known() {
+    let table = toml::from_str::<toml::value::Table>(
+        r#"
+command = "mdbook-scientific"
+renderers = ["html"]
+before = ["links"]
+after = ["index"]
+optional = true
+"#,
+    )
+    .unwrap();
+    let (_, unknown) = ScientificConfig::parse(&table).unwrap();
+    assert!(unknown.is_empty(), "{:?}", unknown);
+}
+
+#[test]
+fn config_invalid_type_names_key() {
+    assert_matches!(config("fragment_path = 3"), Err(Error::InvalidConfig { key, .. }) => {
+        assert_eq!(key, "fragment_path");
+    });
+}
+
+#[test]
+fn config_invalid_zoom_names_key() {
+    assert_matches!(config("inline_zoom = -1.0"), Err(Error::InvalidConfig { key, .. }) => {
+        assert_eq!(key, "inline_zoom");
+    });
+}
+
+#[test]
+fn config_assets_output() {
+    for invalid in ["", "../assets", "/assets"] {
+        let cfg = config(&format!("assets_output = {:?}", invalid));
+        assert_matches!(cfg, Err(Error::InvalidConfig { key, .. }) => {
+            assert_eq!(key, "assets_output");
+        });
+    }
+    assert!(config(r#"assets_output = "assets/scientific""#).is_ok());
+}
+
+#[test]
+fn config_citation_style() {
+    assert_eq!(
+        config(r#"citation_style = "author-year""#)
+            .unwrap()
+            .citation_style,
+        CitationStyle::AuthorYear
+    );
+    assert_matches!(config(r#"citation_style = "{foo}""#), Err(Error::InvalidConfig { key, .. }) => {
+        assert_eq!(key, "citation_style");
+    });
+}
+
+#[test]
+fn config_numbering() {
+    let cfg = config("numbering = { scheme = \"global\", separator = \"-\" }").unwrap();
+    assert_eq!(
+        cfg.numbering,
+        Numbering {
+            scheme: NumberingScheme::Global,
+            separator: "-".to_owned()
+        }
+    );
+    assert_eq!(
+        config("").unwrap().numbering.scheme,
+        NumberingScheme::Section
+    );
+    assert_matches!(config("numbering = { scheme = \"part\" }"), Err(Error::InvalidConfig { key, .. }) => {
+        assert_eq!(key, "numbering.scheme");
+    });
+}
+
+#[test]
+fn config_jobs() {
+    assert_eq!(config("jobs = 3").unwrap().jobs(), 3);
+    assert!(config("").unwrap().jobs() >= 1);
+    assert_matches!(config("jobs = 0"), Err(Error::InvalidConfig { key, .. }) => {
+        assert_eq!(key, "jobs");
+    });
+}
+
+#[test]
+fn config_math_backend() {
+    assert_eq!(config("").unwrap().math_backend, MathBackend::Svg);
+    assert_eq!(
+        config(r#"math_backend = "mathml""#).unwrap().math_backend,
+        MathBackend::Mathml
+    );
+    assert_matches!(config(r#"math_backend = "katex""#), Err(Error::InvalidConfig { key, .. }) => {
+        assert_eq!(key, "math_backend");
+    });
+}
+
+#[test]
+fn config_syntax() {
+    assert_eq!(config("").unwrap().syntax, Syntax::Native);
+    assert_eq!(
+        config(r#"syntax = "pandoc""#).unwrap().syntax,
+        Syntax::Pandoc
+    );
+    assert_matches!(config(r#"syntax = "commonmark""#), Err(Error::InvalidConfig { key, .. }) => {
+        assert_eq!(key, "syntax");
+    });
+}
+
+#[test]
+fn config_macros_and_preamble() {
+    let cfg = config(
+        r#"
+preamble = "preamble.tex"
+[macros]
+R = '\mathbb{R}'
+'\abs' = '\left|#1\right|'
+"#,
+    )
+    .unwrap();
+    assert_eq!(
+        cfg.macro_definitions(),
+        "\\newcommand{\\R}{\\mathbb{R}}\n\\newcommand{\\abs}[1]{\\left|#1\\right|}\n"
+    );
+
+    let root = std::env::temp_dir().join("mdbook-scientific-preamble");
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("preamble.tex"), "\\usepackage{physics}").unwrap();
+    let preamble = cfg.latex_preamble(&root).unwrap();
+    assert!(preamble.starts_with("\\usepackage{physics}\n\\newcommand{\\R}"));
+
+    let template = fragments::Template::new(&preamble);
+    assert!(template
+        .source()
+        .contains("\\usepackage{physics}\n\\newcommand{\\R}{\\mathbb{R}}\n\\newcommand{\\abs}[1]{\\left|#1\\right|}\n\\begin{document}"));
+    assert_ne!(template, fragments::Template::default());
+
+    assert_matches!(config("[macros]\n'R1' = 'x'"), Err(Error::InvalidConfig { key, .. }) => {
+        assert_eq!(key, "macros.R1");
+    });
+    assert_matches!(config("preamble = 'missing.tex'").unwrap().latex_preamble(&root), Err(Error::InvalidConfig { key, .. }) => {
+        assert_eq!(key, "preamble");
+    });
+}
+
 #[test]
-fn end2end() {}
+fn config_preamble_profiles() {
+    let cfg = config(
+        r#"
+[preambles.physics]
+packages = ["physics", "siunitx"]
+macros = { ket = '\left|#1\right\rangle' }
+"#,
+    )
+    .unwrap();
+    assert_eq!(
+        cfg.preambles["physics"].latex(),
+        "\\usepackage{physics}\n\\usepackage{siunitx}\n\\newcommand{\\ket}[1]{\\left|#1\\right\\rangle}\n"
+    );
+    assert_matches!(config("[preambles.x.macros]\n'a-b' = 'x'"), Err(Error::InvalidConfig { key, .. }) => {
+        assert_eq!(key, "preambles.x.macros.a-b");
+    });
+}
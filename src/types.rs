@@ -1,7 +1,255 @@
 use crate::errors;
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
+/// Keys of the preprocessor table which are owned by `mdbook` itself.
+const MDBOOK_KEYS: &[&str] = &["command", "renderers", "before", "after", "optional"];
+
+/// Typed representation of the `[preprocessor.scientific]` table in `book.toml`
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default)]
+pub struct ScientificConfig {
+    /// Directory to store the intermediate fragments in, relative to the book root.
+    pub fragment_path: PathBuf,
+    /// Directory to look up assets, relative to the book root.
+    pub assets: PathBuf,
+    /// Directory the used fragments are copied and rendered mermaid charts written to, relative
+    /// to the book's `src` directory, which `mdbook` serves as is.
+    pub assets_output: PathBuf,
+    /// BibTeX file to cite from with `$ref:bib:<key>$`.
+    pub bibliography: Option<PathBuf>,
+    /// Checkout of [bib2xhtml](https://github.com/dspinellis/bib2xhtml), used instead of
+    /// the native bibliography rendering if set.
+    pub bib2xhtml: Option<PathBuf>,
+    /// Style of the citation labels, in text and in the bibliography.
+    pub citation_style: CitationStyle,
+    /// Keys to list in the bibliography without citing them, `*` lists all.
+    pub nocite: Vec<String>,
+    /// LaTeX file whose content is added to the preamble of every equation, relative to the
+    /// book root.
+    pub preamble: Option<PathBuf>,
+    /// Macros available in every equation by name, i.e. `R = '\mathbb{R}'` defines `\R`.
+    pub macros: BTreeMap<String, String>,
+    /// Named sets of packages and macros, loaded by chapters and blocks on demand.
+    pub preambles: BTreeMap<String, PreambleProfile>,
+    /// Syntax of math and cross-references in the chapters.
+    pub syntax: Syntax,
+    /// How equations are shown in the html.
+    pub math_backend: MathBackend,
+    /// Script typesetting the math of the `client` backend, relative to the book's `src`
+    /// directory or a url. Included by every chapter containing math.
+    pub client_script: Option<String>,
+    /// How rendered svgs are embedded into the html.
+    pub embed: Embed,
+    /// Numbering of figures and equations.
+    pub numbering: Numbering,
+    /// Number of fragments rendered concurrently, defaults to the available parallelism.
+    pub jobs: Option<usize>,
+    /// Render equations in batches, one `latex` run per batch instead of per equation.
+    pub batch: bool,
+    /// Zoom factor of inline equations `$..$`.
+    pub inline_zoom: f32,
+    /// Zoom factor of equation blocks `$$..$$`.
+    pub block_zoom: f32,
+    /// Zoom factor of `latex` and `gnuplot` figures.
+    pub figure_zoom: f32,
+    /// Fail the build on errors, otherwise they are reported as warnings. Either way all
+    /// errors are collected and the offending source is replaced by a placeholder.
+    pub strict: bool,
+}
+
+impl Default for ScientificConfig {
+    fn default() -> Self {
+        Self {
+            fragment_path: PathBuf::from("fragments/"),
+            assets: PathBuf::from("src/"),
+            assets_output: PathBuf::from("assets"),
+            bibliography: None,
+            bib2xhtml: None,
+            citation_style: CitationStyle::default(),
+            nocite: Vec::new(),
+            preamble: None,
+            macros: BTreeMap::new(),
+            preambles: BTreeMap::new(),
+            syntax: Syntax::default(),
+            math_backend: MathBackend::default(),
+            client_script: None,
+            embed: Embed::default(),
+            numbering: Numbering::default(),
+            jobs: None,
+            batch: false,
+            inline_zoom: 1.3,
+            block_zoom: 1.6,
+            figure_zoom: 1.0,
+            strict: true,
+        }
+    }
+}
+
+impl ScientificConfig {
+    /// Deserialize and validate the preprocessor table.
+    ///
+    /// Unknown keys are reported as warnings, invalid values are an error naming the key.
+    pub fn from_table(table: &toml::value::Table) -> Result<Self, errors::Error> {
+        let (config, unknown) = Self::parse(table)?;
+        for key in unknown {
+            eprintln!("Warning: Unknown key `{key}` in `[preprocessor.scientific]` is ignored");
+        }
+        Ok(config)
+    }
+
+    /// Deserialize and validate the preprocessor table, along with the keys neither this
+    /// preprocessor nor `mdbook` knows.
+    pub(crate) fn parse(table: &toml::value::Table) -> Result<(Self, Vec<String>), errors::Error> {
+        let mut unknown = Vec::new();
+        let mut track_unknown = |path: serde_ignored::Path<'_>| unknown.push(path.to_string());
+        let de =
+            serde_ignored::Deserializer::new(toml::Value::Table(table.clone()), &mut track_unknown);
+        let config: Self =
+            serde_path_to_error::deserialize(de).map_err(|err| errors::Error::InvalidConfig {
+                key: err.path().to_string(),
+                reason: err.inner().to_string(),
+            })?;
+
+        unknown.retain(|key| !MDBOOK_KEYS.contains(&key.as_str()));
+
+        config.validate()?;
+        Ok((config, unknown))
+    }
+
+    /// Definitions of all `macros`
+    pub fn macro_definitions(&self) -> String {
+        macro_definitions(&self.macros)
+    }
+
+    /// The configured `preamble` followed by the `macros`, added to every equation
+    pub fn latex_preamble(&self, root: &Path) -> Result<String, errors::Error> {
+        let mut preamble = match self.preamble {
+            Some(ref path) => std::fs::read_to_string(root.join(path)).map_err(|err| {
+                errors::Error::InvalidConfig {
+                    key: "preamble".to_owned(),
+                    reason: format!("failed to read `{}`: {}", path.display(), err),
+                }
+            })?,
+            None => String::new(),
+        };
+        if !preamble.is_empty() && !preamble.ends_with('\n') {
+            preamble.push('\n');
+        }
+        preamble += &self.macro_definitions();
+        Ok(preamble)
+    }
+
+    /// Directory the used fragments are copied to, `src` is the book's source directory
+    pub fn assets_output_path(&self, src: &Path) -> PathBuf {
+        src.join(&self.assets_output)
+    }
+
+    /// Number of fragments rendered concurrently
+    pub fn jobs(&self) -> usize {
+        self.jobs.unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(usize::from)
+                .unwrap_or(1)
+        })
+    }
+
+    fn validate(&self) -> Result<(), errors::Error> {
+        let zooms = [
+            ("inline_zoom", self.inline_zoom),
+            ("block_zoom", self.block_zoom),
+            ("figure_zoom", self.figure_zoom),
+        ];
+        for (key, zoom) in zooms {
+            if !(zoom.is_finite() && zoom > 0.) {
+                return Err(errors::Error::InvalidConfig {
+                    key: key.to_owned(),
+                    reason: format!("zoom must be a positive number, but is {zoom}"),
+                });
+            }
+        }
+        // `src` itself would mix the copies with the chapters
+        if self.assets_output.as_os_str().is_empty()
+            || !self
+                .assets_output
+                .components()
+                .all(|component| matches!(component, std::path::Component::Normal(_)))
+        {
+            return Err(errors::Error::InvalidConfig {
+                key: "assets_output".to_owned(),
+                reason: "must be a relative path within the `src` directory".to_owned(),
+            });
+        }
+        let macros = self.macros.keys().map(|name| format!("macros.{name}"));
+        let profile_macros = self.preambles.iter().flat_map(|(profile, preamble)| {
+            preamble
+                .macros
+                .keys()
+                .map(move |name| format!("preambles.{profile}.macros.{name}"))
+        });
+        for key in macros.chain(profile_macros) {
+            let name = key.rsplit('.').next().unwrap_or_default();
+            let command = name.strip_prefix('\\').unwrap_or(name);
+            if command.is_empty() || !command.chars().all(|c| c.is_ascii_alphabetic()) {
+                return Err(errors::Error::InvalidConfig {
+                    key,
+                    reason: "macro names consist of letters only".to_owned(),
+                });
+            }
+        }
+        if self.jobs == Some(0) {
+            return Err(errors::Error::InvalidConfig {
+                key: "jobs".to_owned(),
+                reason: "at least one job is required".to_owned(),
+            });
+        }
+        if self.bib2xhtml.is_some() && self.bibliography.is_none() {
+            eprintln!("Warning: `bib2xhtml` is set, but there is no `bibliography` to render");
+        }
+        Ok(())
+    }
+}
+
+/// Definitions of `macros` by name, `#1` to `#9` in a body declare arguments
+fn macro_definitions(macros: &BTreeMap<String, String>) -> String {
+    let mut definitions = String::new();
+    for (name, body) in macros {
+        let name = name.trim_start_matches('\\');
+        definitions += &match macro_arity(body) {
+            Some(args) => format!("\\newcommand{{\\{}}}[{}]{{{}}}\n", name, args, body),
+            None => format!("\\newcommand{{\\{}}}{{{}}}\n", name, body),
+        };
+    }
+    definitions
+}
+
+/// Number of arguments of a macro, the highest `#1` to `#9` used in its body
+pub(crate) fn macro_arity(body: &str) -> Option<usize> {
+    (1..=9)
+        .rev()
+        .find(|arg| body.contains(&format!("#{}", arg)))
+}
+
+/// A named set of packages and macros, only loaded by the chapters and blocks using it
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(default)]
+pub struct PreambleProfile {
+    pub packages: Vec<String>,
+    pub macros: BTreeMap<String, String>,
+}
+
+impl PreambleProfile {
+    /// Preamble loading the packages and defining the macros
+    pub fn latex(&self) -> String {
+        let mut latex = String::new();
+        for package in &self.packages {
+            latex += &format!("\\usepackage{{{}}}\n", package);
+        }
+        latex + &macro_definitions(&self.macros)
+    }
+}
+
 /// Enum covering all supported renderers
 ///
 /// Typesafety first.
@@ -27,6 +275,132 @@ impl FromStr for SupportedRenderer {
     }
 }
 
+/// Syntax of math and cross-references in the chapters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Syntax {
+    /// `$$ref:equ:<name>` headers and `$ref:fig:<name>$` references only
+    #[default]
+    Native,
+    /// Additionally `{#eq:<name>}` after blocks, `![..](..){#fig:<name>}`, `@eq:<name>` and
+    /// `[@key]`, as understood by Pandoc with pandoc-crossref
+    Pandoc,
+}
+
+/// How equations are shown in the html
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MathBackend {
+    /// Rendered to svg with `latex` and `dvisvgm`
+    #[default]
+    Svg,
+    /// Converted to `<math>` elements, only unsupported constructs are rendered to svg
+    Mathml,
+    /// Kept as `\(..\)` and `\[..\]` for KaTeX or MathJax in the browser
+    Client,
+}
+
+/// How rendered svgs are embedded into the html
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Embed {
+    /// `<object data="..">`
+    #[default]
+    Object,
+    /// `<img src="..">`, with the source as alternative text
+    Img,
+    /// The `<svg>` itself, as part of the page
+    Inline,
+}
+
+/// Scope within which figures and equations are numbered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NumberingScheme {
+    /// `3.2`, numbered within each top level chapter
+    Chapter,
+    /// `3.1.4`, numbered within each section
+    #[default]
+    Section,
+    /// `17`, numbered throughout the whole book
+    Global,
+}
+
+/// The `[preprocessor.scientific.numbering]` table
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(default)]
+pub struct Numbering {
+    pub scheme: NumberingScheme,
+    /// Separator between the section number and the counter
+    pub separator: String,
+}
+
+impl Default for Numbering {
+    fn default() -> Self {
+        Self {
+            scheme: NumberingScheme::default(),
+            separator: ".".to_owned(),
+        }
+    }
+}
+
+/// Style of citation labels, used for `$ref:bib:<key>$` and the bibliography
+#[derive(Debug, Clone, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub enum CitationStyle {
+    /// `[1]`, numbered in order of first citation
+    #[default]
+    Numeric,
+    /// `[Ein05]`, abbreviated author names and the year
+    Alpha,
+    /// `(Einstein, 1905)`, `(Smith et al., 2020)`
+    AuthorYear,
+    /// User supplied template with placeholders, i.e. `[{author} {year}]`
+    Template(String),
+}
+
+impl CitationStyle {
+    /// Placeholders available in a citation template
+    pub const PLACEHOLDERS: &'static [&'static str] =
+        &["key", "number", "author", "authors", "year", "title"];
+}
+
+impl TryFrom<String> for CitationStyle {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Ok(match s.as_str() {
+            "numeric" => Self::Numeric,
+            "alpha" => Self::Alpha,
+            "author-year" => Self::AuthorYear,
+            template if template.contains('{') => {
+                for placeholder in template
+                    .split('{')
+                    .skip(1)
+                    .map(|rest| rest.split_once('}').map(|(name, _)| name))
+                {
+                    match placeholder {
+                        Some(name) if Self::PLACEHOLDERS.contains(&name) => {}
+                        Some(name) => {
+                            return Err(format!(
+                                "unknown placeholder `{{{name}}}` in citation template, expected one of {}",
+                                Self::PLACEHOLDERS.join(", ")
+                            ))
+                        }
+                        None => return Err("unclosed `{` in citation template".to_owned()),
+                    }
+                }
+                Self::Template(s)
+            }
+            other => {
+                return Err(format!(
+                    "unknown citation style `{other}`, expected `numeric`, `alpha`, `author-year` or a template"
+                ))
+            }
+        })
+    }
+}
+
 /// A dollar sign or maybe two, or three.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Dollar<'a> {
@@ -51,7 +425,8 @@ impl<'a> AsRef<str> for Dollar<'a> {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+/// Position within a chapter, in characters
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct LiCo {
     /// Base 1 line number
     pub lineno: usize,
@@ -72,6 +447,75 @@ pub struct Content<'a> {
     pub byte_range: std::ops::Range<usize>,
     /// Enclosing delimiter
     pub delimiter: Dollar<'a>,
+    /// Markers of the blockquotes and list items in front of the opening delimiter of a
+    /// block, which the lines within it repeat
+    pub container: &'a str,
+}
+
+impl<'a> Content<'a> {
+    /// The end `tail` of this content, starting where it does within the chapter
+    pub fn tail(&self, tail: &'a str) -> Self {
+        let skipped = &self.s[..self.s.len() - tail.len()];
+        let start = match skipped.rfind('\n') {
+            Some(newline) => LiCo {
+                lineno: self.start.lineno + skipped.matches('\n').count(),
+                column: skipped[newline + 1..].chars().count() + 1,
+            },
+            None => LiCo {
+                lineno: self.start.lineno,
+                column: self.start.column + skipped.chars().count(),
+            },
+        };
+        Self {
+            s: tail,
+            start,
+            byte_range: self.byte_range.start + skipped.len()..self.byte_range.end,
+            ..self.clone()
+        }
+    }
+}
+
+impl<'a> Content<'a> {
+    /// `s` without the container markers in front of each of its lines, as passed on to the
+    /// tools typesetting it
+    pub fn math(&self) -> std::borrow::Cow<'a, str> {
+        if self.container.is_empty() {
+            return std::borrow::Cow::Borrowed(self.s);
+        }
+        let mut math = String::with_capacity(self.s.len());
+        for (idx, line) in self.s.split_inclusive('\n').enumerate() {
+            // the first line only starts with the markers if it starts the markdown line
+            if idx == 0 && self.start.column != 1 {
+                math.push_str(line);
+            } else {
+                math.push_str(strip_container(line, self.container));
+            }
+        }
+        std::borrow::Cow::Owned(math)
+    }
+}
+
+/// Strip the markers of `container` from the start of `line`, as far as they are repeated.
+///
+/// Lazy continuation lines may omit some of the `>`, list items are indented up to the width
+/// of the markers.
+fn strip_container<'a>(line: &'a str, container: &str) -> &'a str {
+    let mut quotes = container.matches('>').count();
+    let mut width = container.chars().count();
+    let mut rest = line;
+    for c in line.chars() {
+        if width == 0 {
+            break;
+        }
+        match c {
+            '>' if quotes > 0 => quotes -= 1,
+            ' ' | '\t' => {}
+            _ => break,
+        }
+        width -= 1;
+        rest = &rest[c.len_utf8()..];
+    }
+    rest
 }
 
 impl<'a> AsRef<str> for Content<'a> {
@@ -94,6 +538,29 @@ pub struct Replacement<'a> {
     /// Intermediate representation if there is any, directly usable with latex/tectonic backends;.
     pub(crate) intermediate: Option<String>,
     pub svg: PathBuf,
+    /// Extents around the baseline, only known for inline equations
+    pub metrics: Option<Metrics>,
+}
+
+/// Extents of a rendered formula above and below its baseline, in `pt`
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Metrics {
+    pub height: f32,
+    pub depth: f32,
+}
+
+impl Metrics {
+    /// Font size of the text the svgs are sized for, mdBook's `16px`
+    const TEXT_SIZE_PX: f32 = 16.;
+
+    /// Css pixels per point, the unit of the svg dimensions `dvisvgm` writes
+    const PX_PER_PT: f32 = 96. / 72.;
+
+    /// Css `vertical-align`, which moves the baseline of the formula onto the one of the text
+    pub fn vertical_align(&self) -> String {
+        let depth_px = self.depth * Self::PX_PER_PT;
+        format!("{:.3}em", -depth_px / Self::TEXT_SIZE_PX)
+    }
 }
 
 impl<'a> Replacement<'a> {